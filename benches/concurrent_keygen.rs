@@ -0,0 +1,70 @@
+//! Keygen throughput over the in-memory store, sessions run one after the other vs concurrently, and the latency of a
+//! cheap round while the concurrent sessions run. The Paillier key generation and the PDL proofs run on the blocking
+//! pool, so with only two async workers the concurrent keygens still scale and the cheap round is not stuck behind them.
+//! `cargo bench --features test-utils --bench concurrent_keygen`
+use gotham_engine::memory_store::InMemoryStore;
+use gotham_engine::test_utils;
+use rocket::http::Status;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const SESSIONS: usize = 16;
+const WORKERS: usize = 2;
+
+#[tokio::main(flavor = "multi_thread", worker_threads = 2)]
+async fn main() {
+    let client = Arc::new(test_utils::client(Arc::new(InMemoryStore::default())).await);
+
+    let start = Instant::now();
+    for i in 0..SESSIONS {
+        test_utils::keygen(&client, &format!("sequential-{}", i)).await;
+    }
+    let sequential = start.elapsed();
+
+    let (id, _) = test_utils::keygen(&client, "latency").await;
+    let start = Instant::now();
+    let handles: Vec<_> = (0..SESSIONS)
+        .map(|i| {
+            let client = client.clone();
+            tokio::spawn(async move {
+                test_utils::keygen(&client, &format!("concurrent-{}", i)).await;
+            })
+        })
+        .collect();
+    let mut latencies = Vec::new();
+    while handles.iter().any(|handle| !handle.is_finished()) {
+        let request = Instant::now();
+        let response =
+            test_utils::get_as(&client, "latency", format!("/ecdsa/{}/public", id)).await;
+        assert_eq!(response.status(), Status::Ok);
+        latencies.push(request.elapsed());
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    for handle in handles {
+        handle.await.expect("keygen session completes");
+    }
+    let concurrent = start.elapsed();
+
+    println!(
+        "sequential: {} keygens in {:?} ({:.2}/s)",
+        SESSIONS,
+        sequential,
+        SESSIONS as f64 / sequential.as_secs_f64()
+    );
+    println!(
+        "concurrent: {} keygens in {:?} ({:.2}/s) on {} async workers",
+        SESSIONS,
+        concurrent,
+        SESSIONS as f64 / concurrent.as_secs_f64(),
+        WORKERS
+    );
+    latencies.sort();
+    if let Some(max) = latencies.last() {
+        println!(
+            "public key during the concurrent keygens: {} requests, median {:?}, max {:?}",
+            latencies.len(),
+            latencies[latencies.len() / 2],
+            max
+        );
+    }
+}
@@ -0,0 +1,129 @@
+//! Garbage collection of the intermediate KeyGen and rotation material. Once the master key is set the secrets of the
+//! previous rounds and the [KeyGenState] are deleted right away, sessions that never get there are expired by a TTL
+//! based sweeper. The [KeyGenState] is written before any secret, so the sweeper finds every session that stored one.
+//! Likewise a rotation claims its [RotationState] before it stores a secret, and the state is deleted together with
+//! them once the rotation completes or expires.
+use crate::traits::Db;
+use crate::types::{DatabaseError, DbIndex, EcdsaStruct, KeyGenStage, KeyGenState, RotationState};
+
+use chrono::Utc;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tracing::{error, info};
+
+/// The tables only needed until [EcdsaStruct::Party1MasterKey] is set
+pub const INTERMEDIATE_KEYGEN_STRUCTS: [EcdsaStruct; 14] = [
+    EcdsaStruct::KeyGenFirstMsg,
+    EcdsaStruct::CommWitness,
+    EcdsaStruct::EcKeyPair,
+    EcdsaStruct::PaillierKeyPair,
+    EcdsaStruct::Party1Private,
+    EcdsaStruct::Party2Public,
+    EcdsaStruct::PDLProver,
+    EcdsaStruct::PDLDecommit,
+    EcdsaStruct::Alpha,
+    EcdsaStruct::Party2PDLFirstMsg,
+    EcdsaStruct::CCKeyGenFirstMsg,
+    EcdsaStruct::CCCommWitness,
+    EcdsaStruct::CCEcKeyPair,
+    EcdsaStruct::CC,
+];
+
+/// The tables holding the state of an ongoing rotation, [EcdsaStruct::RotationState] last
+pub const ROTATION_STRUCTS: [EcdsaStruct; 9] = [
+    EcdsaStruct::RotateCommit,
+    EcdsaStruct::RotateRandom,
+    EcdsaStruct::RotateFirstMsg,
+    EcdsaStruct::RotatePrivateNew,
+    EcdsaStruct::RotatePDLDecommit,
+    EcdsaStruct::RotateAlpha,
+    EcdsaStruct::RotateParty2PDLFirstMsg,
+    EcdsaStruct::RotateParty1PDLFirstMsg,
+    EcdsaStruct::RotationState,
+];
+
+/// Deletes the intermediate KeyGen material of the session `key`
+pub async fn delete_intermediate(db: &dyn Db, key: &DbIndex) -> Result<(), DatabaseError> {
+    for table in INTERMEDIATE_KEYGEN_STRUCTS.iter() {
+        db.delete(key, table).await?;
+    }
+    Ok(())
+}
+
+/// Deletes the intermediate material and then the [KeyGenState] of a session whose master key is set, so that
+/// [sweep] does not visit it anymore
+pub async fn delete_completed(db: &dyn Db, key: &DbIndex) -> Result<(), DatabaseError> {
+    delete_intermediate(db, key).await?;
+    db.delete(key, &EcdsaStruct::KeyGenState).await
+}
+
+/// Deletes the material of the rotation of `key`, and then its [RotationState]
+pub async fn delete_rotation(db: &dyn Db, key: &DbIndex) -> Result<(), DatabaseError> {
+    for table in ROTATION_STRUCTS.iter() {
+        db.delete(key, table).await?;
+    }
+    Ok(())
+}
+
+/// Deletes every KeyGen session which started more than `ttl` ago and never set its master key, and finishes the
+/// cleanup of completed sessions whose [delete_completed] failed. Rotations which started more than `ttl` ago are
+/// deleted as well. Returns the number of abandoned sessions and rotations deleted
+pub async fn sweep(db: &dyn Db, ttl: Duration) -> Result<usize, DatabaseError> {
+    let now = Utc::now().timestamp();
+    let mut swept = 0;
+    for key in db.keys(&EcdsaStruct::KeyGenState).await? {
+        let state = match db.get(&key, &EcdsaStruct::KeyGenState).await? {
+            Some(state) => state,
+            None => continue,
+        };
+        let state = match state.as_any().downcast_ref::<KeyGenState>() {
+            Some(state) => state,
+            None => continue,
+        };
+        if state.stage == KeyGenStage::MasterKeySet {
+            delete_completed(db, &key).await?;
+            continue;
+        }
+        if now.saturating_sub(state.started_at) < ttl.as_secs() as i64 {
+            continue;
+        }
+
+        delete_intermediate(db, &key).await?;
+        db.delete(&key, &EcdsaStruct::POS).await?;
+        db.delete(&key, &EcdsaStruct::KeyGenState).await?;
+        swept += 1;
+    }
+    for key in db.keys(&EcdsaStruct::RotationState).await? {
+        let state = match db.get(&key, &EcdsaStruct::RotationState).await? {
+            Some(state) => state,
+            None => continue,
+        };
+        let state = match state.as_any().downcast_ref::<RotationState>() {
+            Some(state) => state,
+            None => continue,
+        };
+        if now.saturating_sub(state.started_at) < ttl.as_secs() as i64 {
+            continue;
+        }
+
+        delete_rotation(db, &key).await?;
+        swept += 1;
+    }
+    Ok(swept)
+}
+
+/// Runs [sweep] every `interval` in the background, e.g. with [crate::settings::EngineConfig::keygen_session_ttl_secs]
+pub fn spawn_sweeper(db: Arc<dyn Db>, ttl: Duration, interval: Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            match sweep(db.as_ref(), ttl).await {
+                Ok(0) => {}
+                Ok(swept) => info!("Swept {} abandoned keygen sessions and rotations", swept),
+                Err(e) => error!("Failed to sweep abandoned keygen sessions and rotations: {}", e),
+            }
+        }
+    })
+}
@@ -0,0 +1,355 @@
+//! A ready to use [Db] implementation over DynamoDB. Table names follow [MPCStruct::to_table_name] and the key
+//! attributes are [CUSTOMER_ID_IDENTIFIER] (only for tables that [MPCStruct::require_customer_id]) and [ID_IDENTIFIER].
+//! The customer id is stored on every item regardless, so that [Db::keys] can restore the full [DbIndex].
+//! The endpoint is configurable so that the store can run against DynamoDB Local.
+use crate::traits::{Db, MPCStruct};
+use crate::types::{DatabaseError, DbIndex, EcdsaStruct, CUSTOMER_ID_IDENTIFIER, ID_IDENTIFIER};
+
+use two_party_ecdsa::party_one::Value;
+
+use rocket::async_trait;
+use rusoto_core::{Region, RusotoError};
+use rusoto_dynamodb::{
+    AttributeDefinition, AttributeValue, CreateTableError, CreateTableInput, DeleteItemInput,
+    DynamoDb, DynamoDbClient, GetItemInput, KeySchemaElement, PutItemError, PutItemInput, QueryInput,
+    ScanInput,
+};
+use std::collections::HashMap;
+use tracing::error;
+use zeroize::Zeroizing;
+
+/// The attribute holding the typetag serialized [Value]
+pub const VALUE_IDENTIFIER: &str = "value";
+
+/// DynamoDB backed storage for the state of KeyGen and Sign
+pub struct DynamoDbStore {
+    client: DynamoDbClient,
+    env: String,
+}
+
+impl DynamoDbStore {
+    /// Connects to DynamoDB at the given AWS region
+    pub fn new(region: Region, env: &str) -> Self {
+        DynamoDbStore {
+            client: DynamoDbClient::new(region),
+            env: env.to_string(),
+        }
+    }
+
+    /// Connects to a custom endpoint, e.g. `http://localhost:8000` for DynamoDB Local
+    pub fn with_endpoint(endpoint: &str, env: &str) -> Self {
+        Self::new(
+            Region::Custom {
+                name: "local".to_string(),
+                endpoint: endpoint.to_string(),
+            },
+            env,
+        )
+    }
+
+    /// Creates a table for each [EcdsaStruct] if it does not exist yet. Production tables are expected to be
+    /// provisioned upfront, this is mostly useful against DynamoDB Local.
+    pub async fn create_tables(&self) -> Result<(), DatabaseError> {
+        for table in EcdsaStruct::ALL.iter() {
+            let mut attribute_definitions = vec![AttributeDefinition {
+                attribute_name: ID_IDENTIFIER.to_string(),
+                attribute_type: "S".to_string(),
+            }];
+            let mut key_schema = vec![];
+            if table.require_customer_id() {
+                attribute_definitions.push(AttributeDefinition {
+                    attribute_name: CUSTOMER_ID_IDENTIFIER.to_string(),
+                    attribute_type: "S".to_string(),
+                });
+                key_schema.push(KeySchemaElement {
+                    attribute_name: CUSTOMER_ID_IDENTIFIER.to_string(),
+                    key_type: "HASH".to_string(),
+                });
+                key_schema.push(KeySchemaElement {
+                    attribute_name: ID_IDENTIFIER.to_string(),
+                    key_type: "RANGE".to_string(),
+                });
+            } else {
+                key_schema.push(KeySchemaElement {
+                    attribute_name: ID_IDENTIFIER.to_string(),
+                    key_type: "HASH".to_string(),
+                });
+            }
+
+            let input = CreateTableInput {
+                table_name: table.to_table_name(&self.env),
+                attribute_definitions,
+                key_schema,
+                billing_mode: Some("PAY_PER_REQUEST".to_string()),
+                ..Default::default()
+            };
+            match self.client.create_table(input).await {
+                Ok(_) | Err(RusotoError::Service(CreateTableError::ResourceInUse(_))) => {}
+                Err(e) => {
+                    error!("Failed to create table {}: {}", table.to_table_name(&self.env), e);
+                    return Err(DatabaseError::TableCreationError(-1));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn to_key(key: &DbIndex, table_name: &dyn MPCStruct) -> HashMap<String, AttributeValue> {
+        let mut item = HashMap::new();
+        item.insert(
+            ID_IDENTIFIER.to_string(),
+            AttributeValue {
+                s: Some(key.id.clone()),
+                ..Default::default()
+            },
+        );
+        if table_name.require_customer_id() {
+            item.insert(
+                CUSTOMER_ID_IDENTIFIER.to_string(),
+                AttributeValue {
+                    s: Some(key.customerId.clone()),
+                    ..Default::default()
+                },
+            );
+        }
+        item
+    }
+}
+
+#[async_trait]
+impl Db for DynamoDbStore {
+    async fn insert(
+        &self,
+        key: &DbIndex,
+        table_name: &dyn MPCStruct,
+        value: &dyn Value,
+    ) -> Result<(), DatabaseError> {
+        let mut item = Self::to_key(key, table_name);
+        item.insert(
+            CUSTOMER_ID_IDENTIFIER.to_string(),
+            AttributeValue {
+                s: Some(key.customerId.clone()),
+                ..Default::default()
+            },
+        );
+        //the request owns its own copy, rusoto drops it without zeroizing once sent
+        let value =
+            Zeroizing::new(serde_json::to_string(value).or(Err(DatabaseError::InsertError(-1)))?);
+        item.insert(
+            VALUE_IDENTIFIER.to_string(),
+            AttributeValue {
+                s: Some(value.to_string()),
+                ..Default::default()
+            },
+        );
+
+        let input = PutItemInput {
+            table_name: table_name.to_table_name(&self.env),
+            item,
+            ..Default::default()
+        };
+        self.client.put_item(input).await.map_err(|e| {
+            error!(
+                "Failed to insert into {}: {}",
+                table_name.to_table_name(&self.env),
+                e
+            );
+            DatabaseError::InsertError(-1)
+        })?;
+        Ok(())
+    }
+
+    async fn get(
+        &self,
+        key: &DbIndex,
+        table_name: &dyn MPCStruct,
+    ) -> Result<Option<Box<dyn Value>>, DatabaseError> {
+        let input = GetItemInput {
+            table_name: table_name.to_table_name(&self.env),
+            key: Self::to_key(key, table_name),
+            consistent_read: Some(true),
+            ..Default::default()
+        };
+        let output = self.client.get_item(input).await.map_err(|e| {
+            error!(
+                "Failed to get from {}: {}",
+                table_name.to_table_name(&self.env),
+                e
+            );
+            DatabaseError::ReadError(-1)
+        })?;
+
+        let value = match output
+            .item
+            .and_then(|mut item| item.remove(VALUE_IDENTIFIER))
+            .and_then(|attribute| attribute.s)
+        {
+            None => return Ok(None),
+            Some(value) => Zeroizing::new(value),
+        };
+        let value: Box<dyn Value> = serde_json::from_str(&value).map_err(|e| {
+            error!(
+                "Failed to deserialize value from {}: {}",
+                table_name.to_table_name(&self.env),
+                e
+            );
+            DatabaseError::ReadError(-1)
+        })?;
+        Ok(Some(value))
+    }
+
+    async fn delete(&self, key: &DbIndex, table_name: &dyn MPCStruct) -> Result<(), DatabaseError> {
+        let input = DeleteItemInput {
+            table_name: table_name.to_table_name(&self.env),
+            key: Self::to_key(key, table_name),
+            ..Default::default()
+        };
+        self.client.delete_item(input).await.map_err(|e| {
+            error!(
+                "Failed to delete from {}: {}",
+                table_name.to_table_name(&self.env),
+                e
+            );
+            DatabaseError::DeleteError(-1)
+        })?;
+        Ok(())
+    }
+
+    /// A conditional put on the serialized value
+    async fn compare_and_swap(
+        &self,
+        key: &DbIndex,
+        table_name: &dyn MPCStruct,
+        expected: Option<&dyn Value>,
+        value: &dyn Value,
+    ) -> Result<bool, DatabaseError> {
+        let mut item = Self::to_key(key, table_name);
+        item.insert(
+            CUSTOMER_ID_IDENTIFIER.to_string(),
+            AttributeValue {
+                s: Some(key.customerId.clone()),
+                ..Default::default()
+            },
+        );
+        let value =
+            Zeroizing::new(serde_json::to_string(value).or(Err(DatabaseError::InsertError(-1)))?);
+        item.insert(
+            VALUE_IDENTIFIER.to_string(),
+            AttributeValue {
+                s: Some(value.to_string()),
+                ..Default::default()
+            },
+        );
+
+        let mut input = PutItemInput {
+            table_name: table_name.to_table_name(&self.env),
+            item,
+            ..Default::default()
+        };
+        match expected {
+            None => {
+                input.condition_expression =
+                    Some(format!("attribute_not_exists({})", ID_IDENTIFIER));
+            }
+            Some(expected) => {
+                let mut names = HashMap::new();
+                names.insert("#value".to_string(), VALUE_IDENTIFIER.to_string());
+                let expected = Zeroizing::new(
+                    serde_json::to_string(expected).or(Err(DatabaseError::InsertError(-1)))?,
+                );
+                let mut values = HashMap::new();
+                values.insert(
+                    ":expected".to_string(),
+                    AttributeValue {
+                        s: Some(expected.to_string()),
+                        ..Default::default()
+                    },
+                );
+                input.condition_expression = Some("#value = :expected".to_string());
+                input.expression_attribute_names = Some(names);
+                input.expression_attribute_values = Some(values);
+            }
+        }
+
+        match self.client.put_item(input).await {
+            Ok(_) => Ok(true),
+            Err(RusotoError::Service(PutItemError::ConditionalCheckFailed(_))) => Ok(false),
+            Err(e) => {
+                error!(
+                    "Failed to swap in {}: {}",
+                    table_name.to_table_name(&self.env),
+                    e
+                );
+                Err(DatabaseError::InsertError(-1))
+            }
+        }
+    }
+
+    async fn keys(&self, table_name: &dyn MPCStruct) -> Result<Vec<DbIndex>, DatabaseError> {
+        let mut keys = vec![];
+        let mut exclusive_start_key = None;
+        loop {
+            let input = ScanInput {
+                table_name: table_name.to_table_name(&self.env),
+                projection_expression: Some(format!(
+                    "{}, {}",
+                    CUSTOMER_ID_IDENTIFIER, ID_IDENTIFIER
+                )),
+                exclusive_start_key,
+                ..Default::default()
+            };
+            let output = self.client.scan(input).await.map_err(|e| {
+                error!(
+                    "Failed to scan {}: {}",
+                    table_name.to_table_name(&self.env),
+                    e
+                );
+                DatabaseError::ReadError(-1)
+            })?;
+
+            for mut item in output.items.unwrap_or_default() {
+                let customer_id = item.remove(CUSTOMER_ID_IDENTIFIER).and_then(|a| a.s);
+                let id = item.remove(ID_IDENTIFIER).and_then(|a| a.s);
+                // items written before the customer id was stored on every table are skipped
+                if let (Some(customer_id), Some(id)) = (customer_id, id) {
+                    keys.push(DbIndex {
+                        customerId: customer_id,
+                        id,
+                    });
+                }
+            }
+
+            exclusive_start_key = output.last_evaluated_key;
+            if exclusive_start_key.is_none() {
+                return Ok(keys);
+            }
+        }
+    }
+
+    async fn has_active_share(&self, customerId: &str) -> Result<bool, String> {
+        let mut values = HashMap::new();
+        values.insert(
+            format!(":{}", CUSTOMER_ID_IDENTIFIER),
+            AttributeValue {
+                s: Some(customerId.to_string()),
+                ..Default::default()
+            },
+        );
+        let input = QueryInput {
+            table_name: EcdsaStruct::Party1MasterKey.to_table_name(&self.env),
+            key_condition_expression: Some(format!(
+                "{} = :{}",
+                CUSTOMER_ID_IDENTIFIER, CUSTOMER_ID_IDENTIFIER
+            )),
+            expression_attribute_values: Some(values),
+            limit: Some(1),
+            ..Default::default()
+        };
+        let output = self
+            .client
+            .query(input)
+            .await
+            .map_err(|e| format!("Failed to query active shares: {}", e))?;
+        Ok(output.count.unwrap_or(0) > 0)
+    }
+}
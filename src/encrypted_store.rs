@@ -0,0 +1,419 @@
+//! Envelope encryption at rest on top of any [Db]. Values of the [SECRET_STRUCTS] tables are serialized, encrypted
+//! with AES-256-GCM under a fresh data key bound to their [DbIndex] and table name, and stored as [Sealed] together
+//! with the data key wrapped by a [KeyEncryptionProvider]. Values of other tables, and secret values written before
+//! encryption was enabled, are passed through as is.
+//!
+//! Each [Sealed] records the version of the KEK its data key is wrapped with, so that after a rotation of the KEK
+//! records of both versions are read while [rewrap] moves them to the current one.
+use crate::traits::{Db, KeyEncryptionProvider, MPCStruct, SessionStore};
+use crate::types::{DatabaseError, DbIndex, EcdsaStruct};
+
+use two_party_ecdsa::party_one::Value;
+
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use rocket::async_trait;
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::error;
+use zeroize::Zeroizing;
+
+/// The tables holding secret shares, Paillier keys and the secrets of the protocol rounds
+pub const SECRET_STRUCTS: [EcdsaStruct; 14] = [
+    EcdsaStruct::Party1MasterKey,
+    EcdsaStruct::Party1Private,
+    EcdsaStruct::PaillierKeyPair,
+    EcdsaStruct::EcKeyPair,
+    EcdsaStruct::CommWitness,
+    EcdsaStruct::PDLDecommit,
+    EcdsaStruct::Alpha,
+    EcdsaStruct::CCEcKeyPair,
+    EcdsaStruct::CCCommWitness,
+    EcdsaStruct::EphEcKeyPair,
+    EcdsaStruct::RotateCommit,
+    EcdsaStruct::RotatePrivateNew,
+    EcdsaStruct::RotatePDLDecommit,
+    EcdsaStruct::RotateAlpha,
+];
+
+/// Size in bytes of the AES-256-GCM nonce prepended to a wrapped data key
+const NONCE_SIZE: usize = 12;
+
+/// An encrypted value, all fields hex encoded
+#[derive(Serialize, Deserialize)]
+pub struct Sealed {
+    /// The version of the KEK [Sealed::wrapped_key] is wrapped with
+    #[serde(default)]
+    pub kek_version: u32,
+    /// The data key, wrapped by the [KeyEncryptionProvider]
+    pub wrapped_key: String,
+    pub nonce: String,
+    /// The serialized value encrypted with the data key, with [associated_data] of its key and table
+    pub ciphertext: String,
+}
+
+impl Display for Sealed {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Sealed")
+    }
+}
+
+#[typetag::serde]
+impl Value for Sealed {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn type_name(&self) -> &str {
+        "Sealed"
+    }
+}
+
+/// A [KeyEncryptionProvider] holding the KEKs in memory, typically read from local key files. The highest version
+/// is the current one
+pub struct LocalKek {
+    keks: BTreeMap<u32, Aes256Gcm>,
+}
+
+impl LocalKek {
+    /// A single KEK of version 0
+    pub fn new(kek: &[u8; 32]) -> Self {
+        LocalKek {
+            keks: BTreeMap::from([(0, Aes256Gcm::new(kek.into()))]),
+        }
+    }
+
+    /// Reads a single KEK of version 0 from a file holding the hex of 32 bytes, e.g. the output of
+    /// `openssl rand -hex 32`
+    pub fn from_file(path: &str) -> Result<Self, DatabaseError> {
+        Ok(Self::new(&*read_kek(path)?))
+    }
+
+    /// Adds the KEK `version`, which becomes the current one if it is the highest
+    pub fn with_kek(mut self, version: u32, kek: &[u8; 32]) -> Self {
+        self.keks.insert(version, Aes256Gcm::new(kek.into()));
+        self
+    }
+
+    /// Same as [LocalKek::with_kek] with the KEK read from a key file
+    pub fn with_file(self, version: u32, path: &str) -> Result<Self, DatabaseError> {
+        Ok(self.with_kek(version, &*read_kek(path)?))
+    }
+}
+
+fn read_kek(path: &str) -> Result<Zeroizing<[u8; 32]>, DatabaseError> {
+    let kek = std::fs::read_to_string(path)
+        .map(Zeroizing::new)
+        .map_err(|e| {
+            error!("Failed to read the key file {}: {}", path, e);
+            DatabaseError::ConfigError(-1)
+        })?;
+    let kek = Zeroizing::new(hex::decode(kek.trim()).unwrap_or_default());
+    <[u8; 32]>::try_from(kek.as_slice())
+        .ok()
+        .map(Zeroizing::new)
+        .ok_or_else(|| {
+            error!("The key file {} does not hold the hex of 32 bytes", path);
+            DatabaseError::ConfigError(-1)
+        })
+}
+
+#[async_trait]
+impl KeyEncryptionProvider for LocalKek {
+    fn current_version(&self) -> u32 {
+        //there is always at least the KEK of the constructor
+        *self.keks.keys().next_back().unwrap_or(&0)
+    }
+
+    async fn wrap(&self, data_key: &[u8]) -> Result<Vec<u8>, DatabaseError> {
+        let cipher = self
+            .keks
+            .values()
+            .next_back()
+            .ok_or(DatabaseError::EncryptionError(-1))?;
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(&nonce, data_key)
+            .or(Err(DatabaseError::EncryptionError(-1)))?;
+        Ok([nonce.as_slice(), &ciphertext].concat())
+    }
+
+    async fn unwrap(&self, version: u32, wrapped_key: &[u8]) -> Result<Vec<u8>, DatabaseError> {
+        let cipher = self.keks.get(&version).ok_or_else(|| {
+            error!("Unknown KEK version {}", version);
+            DatabaseError::EncryptionError(-1)
+        })?;
+        if wrapped_key.len() < NONCE_SIZE {
+            return Err(DatabaseError::EncryptionError(-1));
+        }
+        let (nonce, ciphertext) = wrapped_key.split_at(NONCE_SIZE);
+        cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .or(Err(DatabaseError::EncryptionError(-1)))
+    }
+}
+
+/// A [Db], and [SessionStore] if `D` is one, encrypting the values of the [SECRET_STRUCTS] tables of `D`
+pub struct EncryptedDb<D: Db> {
+    inner: D,
+    kek: Arc<dyn KeyEncryptionProvider>,
+}
+
+impl<D: Db> EncryptedDb<D> {
+    pub fn new(inner: D, kek: Arc<dyn KeyEncryptionProvider>) -> Self {
+        EncryptedDb { inner, kek }
+    }
+
+    /// The underlying store, which only ever sees [Sealed] secret values
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    /// The underlying store, e.g. to wrap it again with a rotated [KeyEncryptionProvider]
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+
+    async fn seal(
+        &self,
+        key: &DbIndex,
+        table_name: &dyn MPCStruct,
+        value: &dyn Value,
+    ) -> Result<Sealed, DatabaseError> {
+        let plaintext =
+            Zeroizing::new(serde_json::to_vec(value).or(Err(DatabaseError::InsertError(-1)))?);
+        let mut data_key = Zeroizing::new([0u8; 32]);
+        OsRng.fill_bytes(&mut data_key[..]);
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let aad = associated_data(key, table_name)?;
+        let ciphertext = Aes256Gcm::new((&*data_key).into())
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: &plaintext,
+                    aad: aad.as_bytes(),
+                },
+            )
+            .or(Err(DatabaseError::EncryptionError(-1)))?;
+        Ok(Sealed {
+            kek_version: self.kek.current_version(),
+            wrapped_key: hex::encode(self.kek.wrap(&data_key[..]).await?),
+            nonce: hex::encode(nonce),
+            ciphertext: hex::encode(ciphertext),
+        })
+    }
+
+    /// Decrypts `value` if it is [Sealed], `None` if it is stored as is
+    async fn open(
+        &self,
+        key: &DbIndex,
+        table_name: &dyn MPCStruct,
+        value: &dyn Value,
+    ) -> Result<Option<Box<dyn Value>>, DatabaseError> {
+        let sealed = match value.as_any().downcast_ref::<Sealed>() {
+            Some(sealed) => sealed,
+            None => return Ok(None),
+        };
+        let decode = |field: &str| hex::decode(field).or(Err(DatabaseError::EncryptionError(-1)));
+        let data_key = Zeroizing::new(
+            self.kek
+                .unwrap(sealed.kek_version, &decode(&sealed.wrapped_key)?)
+                .await?,
+        );
+        let nonce = decode(&sealed.nonce)?;
+        if nonce.len() != NONCE_SIZE {
+            return Err(DatabaseError::EncryptionError(-1));
+        }
+        let aad = associated_data(key, table_name)?;
+        let plaintext = Aes256Gcm::new_from_slice(&data_key)
+            .or(Err(DatabaseError::EncryptionError(-1)))?
+            .decrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: &decode(&sealed.ciphertext)?,
+                    aad: aad.as_bytes(),
+                },
+            )
+            .map(Zeroizing::new)
+            .map_err(|_| {
+                error!("Failed to decrypt {}", aad);
+                DatabaseError::EncryptionError(-1)
+            })?;
+        Ok(Some(
+            serde_json::from_slice(&plaintext).or(Err(DatabaseError::ReadError(-1)))?,
+        ))
+    }
+}
+
+/// The data a sealed value is bound to, the json of its [DbIndex] and table name. Unlike [crate::types::idify], no two
+/// keys or tables share it, so that a value opens under its own key only
+pub fn associated_data(key: &DbIndex, table_name: &dyn MPCStruct) -> Result<String, DatabaseError> {
+    serde_json::to_string(&(key, table_name.to_string())).or(Err(DatabaseError::EncryptionError(-1)))
+}
+
+/// Whether the values of `table_name` are encrypted
+pub fn is_secret(table_name: &dyn MPCStruct) -> bool {
+    let name = table_name.to_string();
+    SECRET_STRUCTS.iter().any(|table| table.to_string() == name)
+}
+
+#[async_trait]
+impl<D: Db> Db for EncryptedDb<D> {
+    async fn insert(
+        &self,
+        key: &DbIndex,
+        table_name: &dyn MPCStruct,
+        value: &dyn Value,
+    ) -> Result<(), DatabaseError> {
+        if !is_secret(table_name) {
+            return self.inner.insert(key, table_name, value).await;
+        }
+        let sealed = self.seal(key, table_name, value).await?;
+        self.inner.insert(key, table_name, &sealed).await
+    }
+
+    async fn get(
+        &self,
+        key: &DbIndex,
+        table_name: &dyn MPCStruct,
+    ) -> Result<Option<Box<dyn Value>>, DatabaseError> {
+        match self.inner.get(key, table_name).await? {
+            None => Ok(None),
+            Some(value) => Ok(Some(
+                self.open(key, table_name, value.as_ref())
+                    .await?
+                    .unwrap_or(value),
+            )),
+        }
+    }
+
+    async fn delete(&self, key: &DbIndex, table_name: &dyn MPCStruct) -> Result<(), DatabaseError> {
+        self.inner.delete(key, table_name).await
+    }
+
+    async fn compare_and_swap(
+        &self,
+        key: &DbIndex,
+        table_name: &dyn MPCStruct,
+        expected: Option<&dyn Value>,
+        value: &dyn Value,
+    ) -> Result<bool, DatabaseError> {
+        if !is_secret(table_name) {
+            return self
+                .inner
+                .compare_and_swap(key, table_name, expected, value)
+                .await;
+        }
+        //sealing is randomized, so compare the plaintexts and swap the ciphertext that was read
+        let current = self.inner.get(key, table_name).await?;
+        let plaintext = match &current {
+            None => None,
+            Some(current) => {
+                let opened = self.open(key, table_name, current.as_ref()).await?;
+                let plaintext = opened.as_deref().unwrap_or(current.as_ref());
+                Some(Zeroizing::new(
+                    serde_json::to_string(plaintext).or(Err(DatabaseError::ReadError(-1)))?,
+                ))
+            }
+        };
+        let expected = expected
+            .map(serde_json::to_string)
+            .transpose()
+            .or(Err(DatabaseError::InsertError(-1)))?
+            .map(Zeroizing::new);
+        if plaintext != expected {
+            return Ok(false);
+        }
+        let sealed = self.seal(key, table_name, value).await?;
+        self.inner
+            .compare_and_swap(key, table_name, current.as_deref(), &sealed)
+            .await
+    }
+
+    async fn keys(&self, table_name: &dyn MPCStruct) -> Result<Vec<DbIndex>, DatabaseError> {
+        self.inner.keys(table_name).await
+    }
+
+    async fn has_active_share(&self, customerId: &str) -> Result<bool, String> {
+        self.inner.has_active_share(customerId).await
+    }
+}
+
+#[async_trait]
+impl<D: Db + SessionStore> SessionStore for EncryptedDb<D> {
+    async fn put(
+        &self,
+        key: &DbIndex,
+        table_name: &dyn MPCStruct,
+        value: &dyn Value,
+        ttl: Duration,
+    ) -> Result<(), DatabaseError> {
+        if !is_secret(table_name) {
+            return self.inner.put(key, table_name, value, ttl).await;
+        }
+        let sealed = self.seal(key, table_name, value).await?;
+        self.inner.put(key, table_name, &sealed, ttl).await
+    }
+
+    async fn take(
+        &self,
+        key: &DbIndex,
+        table_name: &dyn MPCStruct,
+    ) -> Result<Option<Box<dyn Value>>, DatabaseError> {
+        match self.inner.take(key, table_name).await? {
+            None => Ok(None),
+            Some(value) => Ok(Some(
+                self.open(key, table_name, value.as_ref())
+                    .await?
+                    .unwrap_or(value),
+            )),
+        }
+    }
+}
+
+/// Moves every record of `db` to the current KEK: the data keys of [Sealed] values wrapped under an older version are
+/// re-wrapped, the ciphertexts are kept, and secret values stored as is are sealed. Records are swapped atomically, so
+/// it can run next to a live engine; a record written concurrently is already under the current KEK and is skipped.
+/// Values of a [SessionStore] expire instead. Returns the number of records updated
+pub async fn rewrap<D: Db>(db: &EncryptedDb<D>) -> Result<usize, DatabaseError> {
+    let current_version = db.kek.current_version();
+    let mut updated = 0;
+    for table in EcdsaStruct::ALL.iter() {
+        for key in db.inner.keys(table).await? {
+            let stored = match db.inner.get(&key, table).await? {
+                Some(stored) => stored,
+                None => continue,
+            };
+            let rewrapped = match stored.as_any().downcast_ref::<Sealed>() {
+                Some(sealed) if sealed.kek_version == current_version => continue,
+                Some(sealed) => {
+                    let wrapped_key = hex::decode(&sealed.wrapped_key)
+                        .or(Err(DatabaseError::EncryptionError(-1)))?;
+                    let data_key =
+                        Zeroizing::new(db.kek.unwrap(sealed.kek_version, &wrapped_key).await?);
+                    Sealed {
+                        kek_version: current_version,
+                        wrapped_key: hex::encode(db.kek.wrap(&data_key).await?),
+                        nonce: sealed.nonce.clone(),
+                        ciphertext: sealed.ciphertext.clone(),
+                    }
+                }
+                None if is_secret(table) => db.seal(&key, table, stored.as_ref()).await?,
+                None => continue,
+            };
+            if db
+                .inner
+                .compare_and_swap(&key, table, Some(stored.as_ref()), &rewrapped)
+                .await?
+            {
+                updated += 1;
+            }
+        }
+    }
+    Ok(updated)
+}
@@ -1,26 +1,139 @@
+use crate::settings::EngineConfig;
+use crate::types::Authenticator;
+
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
 use rocket::request::{self, FromRequest, Request};
 use rocket::{http::Status, outcome::Outcome};
 use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Claims {
     pub sub: String,
 }
+
+/// Verifies RS256 JWTs against a JWKS, checking signature, issuer, audience and expiry.
+/// It is used by the [Claims] guard when [EngineConfig::authenticator] is [Authenticator::Jwt]
+pub struct JwtVerifier {
+    jwks: JwkSet,
+    validation: Validation,
+}
+
+impl JwtVerifier {
+    /// An empty `audience` skips the audience check, e.g. for cognito access tokens which carry a `client_id` instead
+    pub fn new(jwks: JwkSet, issuer: &str, audience: &str) -> Self {
+        let mut validation = Validation::new(Algorithm::RS256);
+        validation.set_issuer(&[issuer]);
+        let mut required_spec_claims = vec!["exp", "iss", "sub"];
+        if !audience.is_empty() {
+            validation.set_audience(&[audience]);
+            required_spec_claims.push("aud");
+        }
+        validation.set_required_spec_claims(&required_spec_claims);
+
+        JwtVerifier { jwks, validation }
+    }
+
+    /// Loads the JWKS from a json file
+    pub fn from_jwks_file(path: &str, issuer: &str, audience: &str) -> Result<Self, String> {
+        let jwks = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read jwks file {}: {}", path, e))?;
+        let jwks: JwkSet = serde_json::from_str(&jwks)
+            .map_err(|e| format!("Failed to parse jwks file {}: {}", path, e))?;
+        Ok(Self::new(jwks, issuer, audience))
+    }
+
+    /// Fetches the JWKS from an endpoint, e.g. `https://cognito-idp.{region}.amazonaws.com/{pool_id}/.well-known/jwks.json`
+    pub async fn from_jwks_url(url: &str, issuer: &str, audience: &str) -> Result<Self, String> {
+        let jwks: JwkSet = reqwest::get(url)
+            .await
+            .map_err(|e| format!("Failed to fetch jwks from {}: {}", url, e))?
+            .json()
+            .await
+            .map_err(|e| format!("Failed to parse jwks from {}: {}", url, e))?;
+        Ok(Self::new(jwks, issuer, audience))
+    }
+
+    /// Loads the JWKS from `jwks`, which is either an http(s) url or a file path
+    pub async fn from_jwks(jwks: &str, issuer: &str, audience: &str) -> Result<Self, String> {
+        if jwks.starts_with("http://") || jwks.starts_with("https://") {
+            Self::from_jwks_url(jwks, issuer, audience).await
+        } else {
+            Self::from_jwks_file(jwks, issuer, audience)
+        }
+    }
+
+    /// Builds the verifier from [EngineConfig::jwks], [EngineConfig::issuer] and [EngineConfig::audience]
+    pub async fn from_config(config: &EngineConfig) -> Result<Self, String> {
+        Self::from_jwks(&config.jwks, &config.issuer, &config.audience).await
+    }
+
+    /// Verifies `token` and returns its claims
+    pub fn verify(&self, token: &str) -> Result<Claims, String> {
+        let header = decode_header(token).map_err(|e| format!("Invalid jwt header: {}", e))?;
+        let kid = header.kid.ok_or("Missing kid in jwt header")?;
+        let jwk = self
+            .jwks
+            .find(&kid)
+            .ok_or(format!("Unknown jwt kid {}", kid))?;
+        let key = DecodingKey::from_jwk(jwk).map_err(|e| format!("Invalid jwk {}: {}", kid, e))?;
+
+        let token = decode::<Claims>(token, &key, &self.validation)
+            .map_err(|e| format!("Invalid jwt: {}", e))?;
+        Ok(token.claims)
+    }
+}
+
 #[rocket::async_trait]
 impl<'a> FromRequest<'a> for Claims {
     type Error = ();
 
     async fn from_request(request: &'a Request<'_>) -> request::Outcome<Self, Self::Error> {
-        let customer_id_header: Vec<_> = request.headers().get("x-customer-id").collect();
-        if let Some(customer_id) = customer_id_header.get(0) {
-            // Deserialize the customer id as Claims
-            let claims = Claims {
-                sub: customer_id.to_string(),
-            };
-            Outcome::Success(claims)
-        } else {
-            // Handle the case when there are no Authorization headers
-            Outcome::Error((Status::BadRequest, ()))
+        //without a managed config the authenticator is unknown, fail closed rather than trust the header
+        let authenticator = match request.rocket().state::<EngineConfig>() {
+            Some(config) => config.authenticator,
+            None => {
+                error!("No EngineConfig is managed, the authenticator is unknown");
+                return Outcome::Error((Status::InternalServerError, ()));
+            }
+        };
+        match authenticator {
+            Authenticator::Jwt => {
+                let verifier = match request.rocket().state::<JwtVerifier>() {
+                    Some(verifier) => verifier,
+                    None => {
+                        error!("Jwt authentication is enabled but no JwtVerifier is managed");
+                        return Outcome::Error((Status::InternalServerError, ()));
+                    }
+                };
+                let token = request
+                    .headers()
+                    .get_one("Authorization")
+                    .and_then(|header| header.strip_prefix("Bearer "));
+                match token.map(|token| verifier.verify(token)) {
+                    Some(Ok(claims)) => Outcome::Success(claims),
+                    Some(Err(e)) => {
+                        warn!("{}", e);
+                        Outcome::Error((Status::Unauthorized, ()))
+                    }
+                    None => Outcome::Error((Status::Unauthorized, ())),
+                }
+            }
+            // passthrough mode, the customer id is set by the authentication layer in front of the engine
+            Authenticator::None => {
+                let customer_id_header: Vec<_> = request.headers().get("x-customer-id").collect();
+                if let Some(customer_id) = customer_id_header.get(0) {
+                    // Deserialize the customer id as Claims
+                    let claims = Claims {
+                        sub: customer_id.to_string(),
+                    };
+                    Outcome::Success(claims)
+                } else {
+                    // Handle the case when there are no Authorization headers
+                    Outcome::Error((Status::BadRequest, ()))
+                }
+            }
         }
     }
 }
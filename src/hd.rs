@@ -0,0 +1,142 @@
+//! Server driven derivation of child keys. The next free position of a key is kept as [HDPos] under
+//! [EcdsaStruct::POS], set to 0 by the first round of KeyGen, and child keys are derived at `[0, pos]`.
+//! The public parts of a key and its children can be read back for party two to cross-check its own state.
+use crate::guarder::Claims;
+use crate::keygen::downcast;
+use crate::settings::EngineConfig;
+use crate::traits::Db;
+use crate::types::{
+    ChildKey, DbIndex, EcdsaStruct, EngineError, MasterPublicKey, SignSecondMsgRequest,
+};
+
+use two_party_ecdsa::curv::elliptic::curves::traits::ECPoint;
+use two_party_ecdsa::kms::ecdsa::two_party::MasterKey1;
+use two_party_ecdsa::party_one::HDPos;
+use two_party_ecdsa::{BigInt, GE};
+
+use rocket::serde::json::Json;
+use rocket::{async_trait, State};
+use std::sync::Arc;
+
+/// How many times an allocation is retried when concurrent allocations race for the same key
+const MAX_ALLOCATION_ATTEMPTS: usize = 16;
+
+#[async_trait]
+pub trait HD {
+    ///allocates the next child position of the key and returns the child public key
+    async fn allocate_child(
+        state: &State<Arc<dyn Db>>,
+        claim: Claims,
+        id: String,
+    ) -> Result<Json<ChildKey>, EngineError> {
+        let db = state.inner();
+        let key = DbIndex {
+            customerId: claim.sub.to_string(),
+            id: id.clone(),
+        };
+        let master_key = db
+            .get(&key, &EcdsaStruct::Party1MasterKey)
+            .await?
+            .ok_or(EngineError::MissingState(id.to_string()))?;
+
+        let mut allocated = None;
+        for _ in 0..MAX_ALLOCATION_ATTEMPTS {
+            let current = db.get(&key, &EcdsaStruct::POS).await?;
+            let pos = match &current {
+                None => 0,
+                Some(current) => downcast::<HDPos>(current.as_ref(), &id)?.pos,
+            };
+            let next = HDPos {
+                pos: pos.checked_add(1).ok_or(EngineError::Internal(format!(
+                    "No child positions left, id: {}",
+                    id
+                )))?,
+            };
+            if db
+                .compare_and_swap(&key, &EcdsaStruct::POS, current.as_deref(), &next)
+                .await?
+            {
+                allocated = Some(pos);
+                break;
+            }
+        }
+        let pos = allocated.ok_or(EngineError::Internal(format!(
+            "Failed to allocate a child position, id: {}",
+            id
+        )))?;
+
+        let master_key = downcast::<MasterKey1>(master_key.as_ref(), &id)?;
+        Ok(Json(child_key(master_key, BigInt::from(0), BigInt::from(pos))))
+    }
+
+    ///returns the joint public key and chain code of the key, and the child key at `[x_pos, y_pos]` if given
+    async fn public_key(
+        state: &State<Arc<dyn Db>>,
+        claim: Claims,
+        id: String,
+        path: Option<(u32, u32)>,
+    ) -> Result<Json<MasterPublicKey>, EngineError> {
+        let db = state.inner();
+        let master_key = db
+            .get(
+                &DbIndex {
+                    customerId: claim.sub.to_string(),
+                    id: id.clone(),
+                },
+                &EcdsaStruct::Party1MasterKey,
+            )
+            .await?
+            .ok_or(EngineError::MissingState(id.to_string()))?;
+        let master_key = downcast::<MasterKey1>(master_key.as_ref(), &id)?;
+
+        Ok(Json(MasterPublicKey {
+            public: master_key.public.q.clone(),
+            public_key: compressed_public_key(&master_key.public.q),
+            chain_code: master_key.chain_code.clone(),
+            child: path.map(|(x_pos, y_pos)| {
+                child_key(master_key, BigInt::from(x_pos), BigInt::from(y_pos))
+            }),
+        }))
+    }
+}
+
+/// Hex of the 33 bytes SEC1 compressed encoding of `public`
+pub fn compressed_public_key(public: &GE) -> String {
+    hex::encode(&public.get_element().serialize()[..])
+}
+
+fn child_key(master_key: &MasterKey1, x_pos: BigInt, y_pos: BigInt) -> ChildKey {
+    let child_master_key = master_key.get_child(vec![x_pos.clone(), y_pos.clone()]);
+    ChildKey {
+        x_pos_child_key: x_pos,
+        y_pos_child_key: y_pos,
+        public_key: compressed_public_key(&child_master_key.public.q),
+        public: child_master_key.public.q,
+    }
+}
+
+/// With [EngineConfig::enforce_allocated_child_keys], fails unless the child key of the Sign request was allocated
+pub(crate) async fn check_allocated(
+    db: &dyn Db,
+    config: &EngineConfig,
+    key: &DbIndex,
+    request: &SignSecondMsgRequest,
+) -> Result<(), EngineError> {
+    if !config.enforce_allocated_child_keys {
+        return Ok(());
+    }
+    let pos = match db.get(key, &EcdsaStruct::POS).await? {
+        None => 0,
+        Some(pos) => downcast::<HDPos>(pos.as_ref(), &key.id)?.pos,
+    };
+    let allocated = request.x_pos_child_key == BigInt::from(0)
+        && request.y_pos_child_key >= BigInt::from(0)
+        && request.y_pos_child_key < BigInt::from(pos);
+    if !allocated {
+        return Err(EngineError::Unauthorized(format!(
+            "Child key [{}, {}] was not allocated, id: {}",
+            request.x_pos_child_key, request.y_pos_child_key, key.id
+        )));
+    }
+    Ok(())
+}
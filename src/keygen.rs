@@ -1,32 +1,36 @@
+use crate::cleanup;
 use crate::guarder::Claims;
-use crate::traits::Db;
-use crate::types::{Alpha, DbIndex, EcdsaStruct};
+use crate::settings::EngineConfig;
+use crate::telemetry::timed_blocking;
+use crate::traits::{Db, IdGenerator};
+use crate::types::{Alpha, DbIndex, EcdsaStruct, EngineError, KeyGenStage, KeyGenState, SessionLocks};
 
-use two_party_ecdsa::{GE, party_one, party_two};
-use two_party_ecdsa::party_one::{KeyGenFirstMsg, DLogProof, HDPos, v, CommWitness, EcKeyPair, Party1Private, PaillierKeyPair};
+use two_party_ecdsa::{BigInt, GE, party_one, party_two};
+use two_party_ecdsa::party_one::{KeyGenFirstMsg, DLogProof, HDPos, v, CommWitness, EcKeyPair, Party1Private, PaillierKeyPair, Value};
 use two_party_ecdsa::party_two::{PDLFirstMessage as Party2PDLFirstMsg};
-use two_party_ecdsa::curv::cryptographic_primitives::twoparty::dh_key_exchange_variant_with_pok_comm::{Party1FirstMessage, Party1SecondMessage};
+use two_party_ecdsa::curv::cryptographic_primitives::twoparty::dh_key_exchange_variant_with_pok_comm::{Party1FirstMessage, Party1SecondMessage, CommWitnessDHPoK, EcKeyPairDHPoK};
 use two_party_ecdsa::kms::chain_code::two_party::party1::ChainCode1;
 use two_party_ecdsa::kms::ecdsa::two_party::{MasterKey1, party1};
 
-use log::{error, warn};
+use chrono::Utc;
 use rocket::serde::json::Json;
 use rocket::{async_trait, State};
-use std::env;
-use tokio::sync::Mutex;
-use uuid::Uuid;
+use std::sync::Arc;
+use tracing::{error, warn, Span};
 
 #[async_trait]
 pub trait KeyGen {
     ///first round of Keygen
     async fn first(
-        state: &State<Mutex<Box<dyn Db>>>,
+        state: &State<Arc<dyn Db>>,
+        ids: &State<Arc<dyn IdGenerator>>,
+        config: &State<EngineConfig>,
         claim: Claims,
-    ) -> Result<Json<(String, KeyGenFirstMsg)>, String> {
-        let db = state.lock().await;
+    ) -> Result<Json<(String, KeyGenFirstMsg)>, EngineError> {
+        let db = state.inner();
 
         //do not run in a local env
-        if env::var("REDIS_ENV").is_ok() {
+        if config.check_active_share {
             match db.has_active_share(&claim.sub).await {
                 Err(e) => {
                     let msg = format!(
@@ -34,16 +38,15 @@ pub trait KeyGen {
                         &claim.sub
                     );
                     error!("{}: {:?}", msg, e);
-                    return Err(format!("{}", msg));
+                    return Err(EngineError::Internal(msg));
                 }
                 Ok(result) => {
                     if result {
                         let msg = format!("User {} already has an active share", &claim.sub);
                         warn!("{}", msg);
-                        let should_fail_keygen = env::var("FAIL_KEYGEN_IF_ACTIVE_SHARE_EXISTS");
-                        if should_fail_keygen.is_ok() && should_fail_keygen.unwrap() == "true" {
+                        if config.fail_keygen_if_active_share_exists {
                             warn!("Abort KeyGen");
-                            return Err(format!("{}", msg));
+                            return Err(EngineError::ActiveShareExists(msg));
                         }
                     }
                 }
@@ -52,7 +55,22 @@ pub trait KeyGen {
 
         let (key_gen_first_msg, comm_witness, ec_key_pair) = MasterKey1::key_gen_first_message();
 
-        let id = Uuid::new_v4().to_string();
+        let id = ids.next_id();
+        Span::current().record("key_id", id.as_str());
+        //the state goes first, so that [cleanup::sweep] finds the secrets below even if the round fails
+        db.insert(
+            &DbIndex {
+                customerId: claim.sub.to_string(),
+                id: id.clone(),
+            },
+            &EcdsaStruct::KeyGenState,
+            &KeyGenState {
+                stage: KeyGenStage::FirstDone,
+                started_at: Utc::now().timestamp(),
+                in_progress: false,
+            },
+        )
+            .await?;
         //save pos 0
         db.insert(
             &DbIndex {
@@ -62,8 +80,7 @@ pub trait KeyGen {
             &EcdsaStruct::POS,
             &HDPos { pos: 0u32 },
         )
-            .await
-            .or(Err("Failed to insert into db"))?;
+            .await?;
         db.insert(
             &DbIndex {
                 customerId: claim.sub.to_string(),
@@ -72,8 +89,7 @@ pub trait KeyGen {
             &EcdsaStruct::KeyGenFirstMsg,
             &key_gen_first_msg,
         )
-            .await
-            .or(Err("Failed to insert into db"))?;
+            .await?;
 
         db.insert(
             &DbIndex {
@@ -83,8 +99,7 @@ pub trait KeyGen {
             &EcdsaStruct::CommWitness,
             &comm_witness,
         )
-            .await
-            .or(Err("Failed to insert into db"))?;
+            .await?;
 
         db.insert(
             &DbIndex {
@@ -94,20 +109,37 @@ pub trait KeyGen {
             &EcdsaStruct::EcKeyPair,
             &ec_key_pair,
         )
-            .await
-            .or(Err("Failed to insert into db"))?;
+            .await?;
 
         Ok(Json((id.clone(), key_gen_first_msg)))
     }
 
     //second round of Keygen
     async fn second(
-        state: &State<Mutex<Box<dyn Db>>>,
+        state: &State<Arc<dyn Db>>,
+        locks: &State<SessionLocks>,
         claim: Claims,
         id: String,
         dlog_proof: Json<DLogProof>,
-    ) -> Result<Json<party1::KeyGenParty1Message2>, String> {
-        let db = state.lock().await;
+    ) -> Result<Json<party1::KeyGenParty1Message2>, EngineError> {
+        let db = state.inner();
+        let _session = locks
+            .lock(&DbIndex {
+                customerId: claim.sub.to_string(),
+                id: id.clone(),
+            })
+            .await;
+        let state = claim_stage(
+            db.as_ref(),
+            &DbIndex {
+                customerId: claim.sub.to_string(),
+                id: id.clone(),
+            },
+            KeyGenStage::FirstDone,
+            "second",
+        )
+            .await?;
+
         let party2_public: GE = dlog_proof.0.pk;
         db.insert(
             &DbIndex {
@@ -117,8 +149,7 @@ pub trait KeyGen {
             &EcdsaStruct::Party2Public,
             &party2_public,
         )
-            .await
-            .or(Err("Failed to insert into db"))?;
+            .await?;
 
         let comm_witness = db
             .get(
@@ -128,9 +159,8 @@ pub trait KeyGen {
                 },
                 &EcdsaStruct::CommWitness,
             )
-            .await
-            .or(Err("Failed to get from db"))?
-            .ok_or(format!("No data for such identifier {}", id))?;
+            .await?
+            .ok_or(EngineError::MissingState(id.to_string()))?;
         let ec_key_pair = db
             .get(
                 &DbIndex {
@@ -139,16 +169,21 @@ pub trait KeyGen {
                 },
                 &EcdsaStruct::EcKeyPair,
             )
-            .await
-            .or(Err("Failed to get from db"))?
-            .ok_or(format!("No data for such identifier {}", id))?;
+            .await?
+            .ok_or(EngineError::MissingState(id.to_string()))?;
 
+        let party_one_comm_witness = take::<CommWitness>(comm_witness, &id)?;
+        let party_one_ec_key_pair = take::<EcKeyPair>(ec_key_pair, &id)?;
+        let dlog_proof = dlog_proof.into_inner();
         let (kg_party_one_second_message, paillier_key_pair, party_one_private) =
-            MasterKey1::key_gen_second_message(
-                comm_witness.as_any().downcast_ref::<CommWitness>().unwrap(),
-                ec_key_pair.as_any().downcast_ref::<EcKeyPair>().unwrap(),
-                &dlog_proof.0,
-            );
+            timed_blocking("paillier_keygen", move || {
+                MasterKey1::key_gen_second_message(
+                    &party_one_comm_witness,
+                    &party_one_ec_key_pair,
+                    &dlog_proof,
+                )
+            })
+            .await?;
 
         db.insert(
             &DbIndex {
@@ -158,8 +193,7 @@ pub trait KeyGen {
             &EcdsaStruct::PaillierKeyPair,
             &paillier_key_pair,
         )
-            .await
-            .or(Err("Failed to insert into db"))?;
+            .await?;
 
         db.insert(
             &DbIndex {
@@ -169,19 +203,47 @@ pub trait KeyGen {
             &EcdsaStruct::Party1Private,
             &party_one_private,
         )
-            .await
-            .or(Err("Failed to insert into db"))?;
+            .await?;
+
+        complete_stage(
+            db.as_ref(),
+            &DbIndex {
+                customerId: claim.sub.to_string(),
+                id: id.clone(),
+            },
+            &state,
+            KeyGenStage::SecondDone,
+        )
+            .await?;
 
         Ok(Json(kg_party_one_second_message))
     }
 
     async fn third(
-        state: &State<Mutex<Box<dyn Db>>>,
+        state: &State<Arc<dyn Db>>,
+        locks: &State<SessionLocks>,
         claim: Claims,
         id: String,
         party_2_pdl_first_message: Json<party_two::PDLFirstMessage>,
-    ) -> Result<Json<party_one::PDLFirstMessage>, String> {
-        let db = state.lock().await;
+    ) -> Result<Json<party_one::PDLFirstMessage>, EngineError> {
+        let db = state.inner();
+        let _session = locks
+            .lock(&DbIndex {
+                customerId: claim.sub.to_string(),
+                id: id.clone(),
+            })
+            .await;
+
+        let state = claim_stage(
+            db.as_ref(),
+            &DbIndex {
+                customerId: claim.sub.to_string(),
+                id: id.clone(),
+            },
+            KeyGenStage::SecondDone,
+            "third",
+        )
+            .await?;
 
         let party_one_private = db
             .get(
@@ -191,18 +253,17 @@ pub trait KeyGen {
                 },
                 &EcdsaStruct::Party1Private,
             )
-            .await
-            .or(Err(format!("Failed to get from DB, id: {}", id)))?
-            .ok_or(format!("No data for such identifier {}", id))?;
+            .await?
+            .ok_or(EngineError::MissingState(id.to_string()))?;
 
+        let party_one_private = take::<Party1Private>(party_one_private, &id)?;
+        let party_2_pdl_first_message = party_2_pdl_first_message.into_inner();
+        let first_message = party_2_pdl_first_message.clone();
         let (party_one_third_message, party_one_pdl_decommit, alpha) =
-            MasterKey1::key_gen_third_message(
-                &party_2_pdl_first_message.0,
-                &party_one_private
-                    .as_any()
-                    .downcast_ref::<Party1Private>()
-                    .unwrap(),
-            );
+            timed_blocking("pdl_prove", move || {
+                MasterKey1::key_gen_third_message(&first_message, &party_one_private)
+            })
+            .await?;
 
         db.insert(
             &DbIndex {
@@ -212,11 +273,7 @@ pub trait KeyGen {
             &EcdsaStruct::PDLDecommit,
             &party_one_pdl_decommit,
         )
-            .await
-            .or(Err(format!(
-                "Failed to insert into DB PDLDecommit, id: {}",
-                id
-            )))?;
+            .await?;
 
         db.insert(
             &DbIndex {
@@ -226,8 +283,7 @@ pub trait KeyGen {
             &EcdsaStruct::Alpha,
             &Alpha { value: alpha },
         )
-            .await
-            .or(Err(format!("Failed to insert into DB Alpha, id: {}", id)))?;
+            .await?;
 
         db.insert(
             &DbIndex {
@@ -235,23 +291,47 @@ pub trait KeyGen {
                 id: id.clone(),
             },
             &EcdsaStruct::Party2PDLFirstMsg,
-            &party_2_pdl_first_message.0,
+            &party_2_pdl_first_message,
         )
-            .await
-            .or(Err(format!(
-                "Failed to insert into DB Party2PDLFirstMsg, id: {}",
-                id
-            )))?;
+            .await?;
+
+        complete_stage(
+            db.as_ref(),
+            &DbIndex {
+                customerId: claim.sub.to_string(),
+                id: id.clone(),
+            },
+            &state,
+            KeyGenStage::ThirdDone,
+        )
+            .await?;
 
         Ok(Json(party_one_third_message))
     }
     async fn fourth(
-        state: &State<Mutex<Box<dyn Db>>>,
+        state: &State<Arc<dyn Db>>,
+        locks: &State<SessionLocks>,
         claim: Claims,
         id: String,
         party_two_pdl_second_message: Json<party_two::PDLSecondMessage>,
-    ) -> Result<Json<party_one::PDLSecondMessage>, String> {
-        let db = state.lock().await;
+    ) -> Result<Json<party_one::PDLSecondMessage>, EngineError> {
+        let db = state.inner();
+        let _session = locks
+            .lock(&DbIndex {
+                customerId: claim.sub.to_string(),
+                id: id.clone(),
+            })
+            .await;
+        let state = claim_stage(
+            db.as_ref(),
+            &DbIndex {
+                customerId: claim.sub.to_string(),
+                id: id.clone(),
+            },
+            KeyGenStage::ThirdDone,
+            "fourth",
+        )
+            .await?;
 
         let party_one_private = db
             .get(
@@ -261,9 +341,8 @@ pub trait KeyGen {
                 },
                 &EcdsaStruct::Party1Private,
             )
-            .await
-            .or(Err(format!("Failed to get from DB, id:{}", id)))?
-            .ok_or(format!("No data for such identifier {}", id))?;
+            .await?
+            .ok_or(EngineError::MissingState(id.to_string()))?;
 
         let party_2_pdl_first_message = db
             .get(
@@ -273,12 +352,8 @@ pub trait KeyGen {
                 },
                 &EcdsaStruct::Party2PDLFirstMsg,
             )
-            .await
-            .or(Err(format!(
-                "Failed to get party 2 pdl first message from DB, id: {}",
-                id
-            )))?
-            .ok_or(format!("No data for such identifier {}", id))?;
+            .await?
+            .ok_or(EngineError::MissingState(id.to_string()))?;
         let party_one_pdl_decommit = db
             .get(
                 &DbIndex {
@@ -287,12 +362,8 @@ pub trait KeyGen {
                 },
                 &EcdsaStruct::PDLDecommit,
             )
-            .await
-            .or(Err(format!(
-                "Failed to get party one pdl decommit, id: {}",
-                id
-            )))?
-            .ok_or(format!("No data for such identifier {}", id))?;
+            .await?
+            .ok_or(EngineError::MissingState(id.to_string()))?;
 
         let alpha = db
             .get(
@@ -302,44 +373,67 @@ pub trait KeyGen {
                 },
                 &EcdsaStruct::Alpha,
             )
-            .await
-            .or(Err(format!("Failed to get alpha from DB, id: {}", id)))?
-            .ok_or(format!("No data for such identifier {}", id))?;
+            .await?
+            .ok_or(EngineError::MissingState(id.to_string()))?;
         // let dl: &mut dyn Value = party_one_pdl_decommit.borrow_mut();
 
-        let res = MasterKey1::key_gen_fourth_message(
-            party_2_pdl_first_message
-                .as_any()
-                .downcast_ref::<Party2PDLFirstMsg>()
-                .unwrap()
-                .clone(),
-            &party_two_pdl_second_message.0,
-            party_one_private
-                .as_any()
-                .downcast_ref::<Party1Private>()
-                .unwrap()
-                .clone(),
-            party_one_pdl_decommit
-                .as_any()
-                .downcast_ref::<party_one::PDLdecommit>()
-                .unwrap()
-                .clone(),
-            alpha
-                .as_any()
-                .downcast_ref::<Alpha>()
-                .unwrap()
-                .value
-                .clone(),
-        );
-        assert!(res.is_ok());
-        Ok(Json(res.unwrap()))
+        let party_2_pdl_first_message = take::<Party2PDLFirstMsg>(party_2_pdl_first_message, &id)?;
+        let party_one_private = take::<Party1Private>(party_one_private, &id)?;
+        let party_one_pdl_decommit = take::<party_one::PDLdecommit>(party_one_pdl_decommit, &id)?;
+        //Alpha zeroizes its value on drop, the value moves out in its place
+        let alpha = std::mem::replace(&mut take::<Alpha>(alpha, &id)?.value, BigInt::from(0));
+        let party_two_pdl_second_message = party_two_pdl_second_message.into_inner();
+        let res = timed_blocking("pdl_verify", move || {
+            MasterKey1::key_gen_fourth_message(
+                party_2_pdl_first_message,
+                &party_two_pdl_second_message,
+                party_one_private,
+                party_one_pdl_decommit,
+                alpha,
+            )
+        })
+        .await?;
+        let party_one_pdl_second_message = res.or(Err(EngineError::VerificationFailed(format!(
+            "PDL proof of party two failed, id: {}",
+            id
+        ))))?;
+
+        complete_stage(
+            db.as_ref(),
+            &DbIndex {
+                customerId: claim.sub.to_string(),
+                id: id.clone(),
+            },
+            &state,
+            KeyGenStage::FourthDone,
+        )
+            .await?;
+
+        Ok(Json(party_one_pdl_second_message))
     }
     async fn chain_code_first_message(
-        state: &State<Mutex<Box<dyn Db>>>,
+        state: &State<Arc<dyn Db>>,
+        locks: &State<SessionLocks>,
         claim: Claims,
         id: String,
-    ) -> Result<Json<Party1FirstMessage>, String> {
-        let db = state.lock().await;
+    ) -> Result<Json<Party1FirstMessage>, EngineError> {
+        let db = state.inner();
+        let _session = locks
+            .lock(&DbIndex {
+                customerId: claim.sub.to_string(),
+                id: id.clone(),
+            })
+            .await;
+        let state = claim_stage(
+            db.as_ref(),
+            &DbIndex {
+                customerId: claim.sub.to_string(),
+                id: id.clone(),
+            },
+            KeyGenStage::FourthDone,
+            "chaincode first",
+        )
+            .await?;
 
         let (cc_party_one_first_message, cc_comm_witness, cc_ec_key_pair1) =
             ChainCode1::chain_code_first_message();
@@ -352,8 +446,7 @@ pub trait KeyGen {
             &EcdsaStruct::CCKeyGenFirstMsg,
             &cc_party_one_first_message,
         )
-            .await
-            .or(Err("Failed to insert into db"))?;
+            .await?;
 
         db.insert(
             &DbIndex {
@@ -363,8 +456,7 @@ pub trait KeyGen {
             &EcdsaStruct::CCCommWitness,
             &cc_comm_witness,
         )
-            .await
-            .or(Err("Failed to insert into db"))?;
+            .await?;
 
         db.insert(
             &DbIndex {
@@ -374,18 +466,46 @@ pub trait KeyGen {
             &EcdsaStruct::CCEcKeyPair,
             &cc_ec_key_pair1,
         )
-            .await
-            .or(Err("Failed to insert into db"))?;
+            .await?;
+
+        complete_stage(
+            db.as_ref(),
+            &DbIndex {
+                customerId: claim.sub.to_string(),
+                id: id.clone(),
+            },
+            &state,
+            KeyGenStage::ChainCodeFirstDone,
+        )
+            .await?;
 
         Ok(Json(cc_party_one_first_message))
     }
     async fn chain_code_second_message(
-        state: &State<Mutex<Box<dyn Db>>>,
+        state: &State<Arc<dyn Db>>,
+        locks: &State<SessionLocks>,
         claim: Claims,
         id: String,
         cc_party_two_first_message_d_log_proof: Json<DLogProof>,
-    ) -> Result<Json<Party1SecondMessage>, String> {
-        let db = state.lock().await;
+    ) -> Result<Json<Party1SecondMessage>, EngineError> {
+        let db = state.inner();
+        let _session = locks
+            .lock(&DbIndex {
+                customerId: claim.sub.to_string(),
+                id: id.clone(),
+            })
+            .await;
+        let state = claim_stage(
+            db.as_ref(),
+            &DbIndex {
+                customerId: claim.sub.to_string(),
+                id: id.clone(),
+            },
+            KeyGenStage::ChainCodeFirstDone,
+            "chaincode second",
+        )
+            .await?;
+
         let cc_comm_witness = db
             .get(
                 &DbIndex {
@@ -394,12 +514,11 @@ pub trait KeyGen {
                 },
                 &EcdsaStruct::CCCommWitness,
             )
-            .await
-            .or(Err("Failed to get from db"))?
-            .ok_or(format!("No data for such identifier {}", id))?;
+            .await?
+            .ok_or(EngineError::MissingState(id.to_string()))?;
 
         let party1_cc_res = ChainCode1::chain_code_second_message(
-            cc_comm_witness.as_any().downcast_ref::<two_party_ecdsa::curv::cryptographic_primitives::twoparty::dh_key_exchange_variant_with_pok_comm::CommWitnessDHPoK>().unwrap().clone(),
+            downcast::<CommWitnessDHPoK>(cc_comm_witness.as_ref(), &id)?.clone(),
             &cc_party_two_first_message_d_log_proof.0,
         );
 
@@ -414,25 +533,13 @@ pub trait KeyGen {
                 },
                 &EcdsaStruct::CCEcKeyPair,
             )
-            .await
-            .or(Err("Failed to get from db"))?
-            .ok_or(format!("No data for such identifier {}", id))?;
+            .await?
+            .ok_or(EngineError::MissingState(id.to_string()))?;
         let party1_cc = ChainCode1::compute_chain_code(
-            &cc_ec_key_pair_party1.as_any().downcast_ref::<two_party_ecdsa::curv::cryptographic_primitives::twoparty::dh_key_exchange_variant_with_pok_comm::EcKeyPairDHPoK>().unwrap().clone(),
+            downcast::<EcKeyPairDHPoK>(cc_ec_key_pair_party1.as_ref(), &id)?,
             party2_pub,
         );
 
-        db.insert(
-            &DbIndex {
-                customerId: claim.sub.to_string(),
-                id: id.clone(),
-            },
-            &EcdsaStruct::CC,
-            &party1_cc,
-        )
-            .await
-            .or(Err("Failed to insert into db"))?;
-
         //set master key
         let party2_public = db
             .get(
@@ -442,9 +549,8 @@ pub trait KeyGen {
                 },
                 &EcdsaStruct::Party2Public,
             )
-            .await
-            .or(Err(format!("Failed to get alpha from DB, id: {}", id)))?
-            .ok_or(format!("No data for such identifier {}", id))?;
+            .await?
+            .ok_or(EngineError::MissingState(id.to_string()))?;
 
         let paillier_key_pair = db
             .get(
@@ -454,21 +560,8 @@ pub trait KeyGen {
                 },
                 &EcdsaStruct::PaillierKeyPair,
             )
-            .await
-            .or(Err(format!("Failed to get alpha from DB, id: {}", id)))?
-            .ok_or(format!("No data for such identifier {}", id))?;
-
-        let party1_cc = db
-            .get(
-                &DbIndex {
-                    customerId: claim.sub.to_string(),
-                    id: id.clone(),
-                },
-                &EcdsaStruct::CC,
-            )
-            .await
-            .or(Err(format!("Failed to get alpha from DB, id: {}", id)))?
-            .ok_or(format!("No data for such identifier {}", id))?;
+            .await?
+            .ok_or(EngineError::MissingState(id.to_string()))?;
 
         let party_one_private = db
             .get(
@@ -478,9 +571,8 @@ pub trait KeyGen {
                 },
                 &EcdsaStruct::Party1Private,
             )
-            .await
-            .or(Err(format!("Failed to get alpha from DB, id: {}", id)))?
-            .ok_or(format!("No data for such identifier {}", id))?;
+            .await?
+            .ok_or(EngineError::MissingState(id.to_string()))?;
 
         let comm_witness = db
             .get(
@@ -490,32 +582,15 @@ pub trait KeyGen {
                 },
                 &EcdsaStruct::CommWitness,
             )
-            .await
-            .or(Err(format!("Failed to get alpha from DB, id: {}", id)))?
-            .ok_or(format!("No data for such identifier {}", id))?;
+            .await?
+            .ok_or(EngineError::MissingState(id.to_string()))?;
 
         let master_key = MasterKey1::set_master_key(
-            &party1_cc
-                .as_any()
-                .downcast_ref::<ChainCode1>()
-                .unwrap()
-                .chain_code,
-            party_one_private
-                .as_any()
-                .downcast_ref::<Party1Private>()
-                .unwrap()
-                .clone(),
-            &comm_witness
-                .as_any()
-                .downcast_ref::<CommWitness>()
-                .unwrap()
-                .public_share,
-            party2_public.as_any().downcast_ref::<GE>().unwrap(),
-            paillier_key_pair
-                .as_any()
-                .downcast_ref::<PaillierKeyPair>()
-                .unwrap()
-                .clone(),
+            &party1_cc.chain_code,
+            take::<Party1Private>(party_one_private, &id)?,
+            &downcast::<CommWitness>(comm_witness.as_ref(), &id)?.public_share,
+            downcast::<GE>(party2_public.as_ref(), &id)?,
+            take::<PaillierKeyPair>(paillier_key_pair, &id)?,
         );
 
         db.insert(
@@ -525,10 +600,135 @@ pub trait KeyGen {
             },
             &EcdsaStruct::Party1MasterKey,
             &master_key,
+        )
+            .await?;
+
+        complete_stage(
+            db.as_ref(),
+            &DbIndex {
+                customerId: claim.sub.to_string(),
+                id: id.clone(),
+            },
+            &state,
+            KeyGenStage::MasterKeySet,
+        )
+            .await?;
+
+        //the master key is set, the intermediate material and state of the session are not needed anymore
+        if let Err(e) = cleanup::delete_completed(
+            db.as_ref(),
+            &DbIndex {
+                customerId: claim.sub.to_string(),
+                id: id.clone(),
+            },
         )
             .await
-            .or(Err("Failed to insert into db"))?;
+        {
+            warn!("Failed to delete the intermediate keygen state, id: {}: {}", id, e);
+        }
 
         Ok(Json(party1_cc_res))
     }
 }
+
+/// Claims a round of the session, failing unless `expected` is its last completed round and no other round of it is
+/// running. The claim is a [Db::compare_and_swap] on the [KeyGenState], so that it holds across engine instances sharing
+/// the Db and not only within the [SessionLocks] of one. Returns the claimed state for [complete_stage]
+async fn claim_stage(
+    db: &dyn Db,
+    key: &DbIndex,
+    expected: KeyGenStage,
+    round: &str,
+) -> Result<KeyGenState, EngineError> {
+    let current = match db.get(key, &EcdsaStruct::KeyGenState).await? {
+        Some(current) => current,
+        //the state of a completed keygen is deleted with its intermediate material
+        None if db.get(key, &EcdsaStruct::Party1MasterKey).await?.is_some() => {
+            return Err(EngineError::OutOfOrder(format!(
+                "round {} of keygen {} expects {:?}, the keygen is complete",
+                round, key.id, expected
+            )))
+        }
+        None => return Err(EngineError::MissingState(key.id.to_string())),
+    };
+    let state = downcast::<KeyGenState>(current.as_ref(), &key.id)?.clone();
+    if state.stage != expected || state.in_progress {
+        return Err(EngineError::OutOfOrder(format!(
+            "round {} of keygen {} expects {:?}, found {:?}{}",
+            round,
+            key.id,
+            expected,
+            state.stage,
+            if state.in_progress { " with a round in progress" } else { "" }
+        )));
+    }
+
+    let claimed = KeyGenState {
+        in_progress: true,
+        ..state
+    };
+    if !db
+        .compare_and_swap(key, &EcdsaStruct::KeyGenState, Some(current.as_ref()), &claimed)
+        .await?
+    {
+        return Err(EngineError::OutOfOrder(format!(
+            "round {} of keygen {} raced with another round",
+            round, key.id
+        )));
+    }
+    Ok(claimed)
+}
+
+/// Completes the round claimed by [claim_stage], moving the session to `stage`. A round that fails after its claim
+/// leaves the session claimed, so that it can not be retried and is left to [cleanup::sweep]
+async fn complete_stage(
+    db: &dyn Db,
+    key: &DbIndex,
+    claimed: &KeyGenState,
+    stage: KeyGenStage,
+) -> Result<(), EngineError> {
+    let next = KeyGenState {
+        stage,
+        in_progress: false,
+        ..claimed.clone()
+    };
+    if !db
+        .compare_and_swap(key, &EcdsaStruct::KeyGenState, Some(claimed), &next)
+        .await?
+    {
+        return Err(EngineError::OutOfOrder(format!(
+            "keygen {} changed while completing {:?}",
+            key.id, stage
+        )));
+    }
+    Ok(())
+}
+
+/// Downcasts a value read from the Db, failing instead of panicking if the stored type is unexpected
+pub(crate) fn downcast<'a, T: 'static>(value: &'a dyn Value, id: &str) -> Result<&'a T, EngineError> {
+    value.as_any().downcast_ref::<T>().ok_or(EngineError::Internal(format!(
+        "Unexpected type {} in the Db, id: {}",
+        value.type_name(),
+        id
+    )))
+}
+
+/// Same as [downcast] but moves the value out of its box, so that secrets are handed over instead of cloned into
+/// copies that are never zeroized
+pub(crate) fn take<T: 'static>(value: Box<dyn Value>, id: &str) -> Result<T, EngineError> {
+    let any = value.as_any();
+    let is_the_value = any.is::<T>()
+        && std::ptr::addr_eq(any as *const dyn std::any::Any, value.as_ref() as *const dyn Value)
+        && std::mem::size_of_val(value.as_ref()) == std::mem::size_of::<T>()
+        && std::mem::align_of_val(value.as_ref()) == std::mem::align_of::<T>();
+    if !is_the_value {
+        return Err(EngineError::Internal(format!(
+            "Unexpected type {} in the Db, id: {}",
+            value.type_name(),
+            id
+        )));
+    }
+    // SAFETY: the box holds a T, `as_any` returned the boxed value itself as a T, with the size and alignment
+    // the box was allocated with
+    Ok(*unsafe { Box::from_raw(Box::into_raw(value) as *mut T) })
+}
@@ -1,7 +1,22 @@
+pub mod cleanup;
+#[cfg(feature = "rusoto_dynamodb")]
+pub mod dynamodb_store;
+pub mod encrypted_store;
 pub mod guarder;
+pub mod hd;
 pub mod keygen;
+pub mod memory_store;
+pub mod policy;
+pub mod redis_store;
+pub mod rotate;
 pub mod routes;
+#[cfg(feature = "rocksdb")]
+pub mod rocksdb_store;
+pub mod settings;
 pub mod sign;
+pub mod telemetry;
+#[cfg(feature = "test-utils")]
+pub mod test_utils;
 pub mod traits;
 pub mod types;
 // type Result<T> = std::result::Result<T, failure::Error>;
@@ -0,0 +1,245 @@
+//! An in-memory [Db] implementation for tests, demos and ephemeral deployments. Nothing survives a restart.
+//! Values go through the same typetag serialization as the persistent stores. It is also a [SessionStore] for the
+//! cross-session sign rounds and an [IncidentSink].
+use crate::traits::{Db, IncidentSink, MPCStruct, SessionStore, TxAuthorization};
+use crate::types::{
+    DatabaseError, DbIndex, EcdsaStruct, SignatureIncident, TxAuthorizationRequest, TxDecision,
+};
+
+use two_party_ecdsa::party_one::Value;
+
+use rocket::async_trait;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use zeroize::Zeroizing;
+
+type ActiveSharePolicy = Box<dyn Fn(&str) -> bool + Send + Sync>;
+type GrantedPolicy = Box<dyn Fn(&TxAuthorizationRequest) -> bool + Send + Sync>;
+
+/// In-memory storage for the state of KeyGen and Sign
+pub struct InMemoryStore {
+    env: String,
+    /// (customerId, id, table name) -> serialized value, wiped once deleted or overwritten
+    values: Mutex<HashMap<(String, String, String), Zeroizing<String>>>,
+    /// (customerId, ssid, table name) -> (expiry, serialized value) of the [SessionStore]
+    sessions: Mutex<HashMap<(String, String, String), (Instant, Zeroizing<String>)>>,
+    /// serialized [SignatureIncident]s in the order they were recorded
+    incidents: Mutex<Vec<String>>,
+    active_share_policy: Option<ActiveSharePolicy>,
+    granted_policy: GrantedPolicy,
+}
+
+impl Default for InMemoryStore {
+    fn default() -> Self {
+        Self::new("local")
+    }
+}
+
+impl InMemoryStore {
+    /// An empty store. By default a customer has an active share once a [EcdsaStruct::Party1MasterKey]
+    /// is stored for them and every tx is granted.
+    pub fn new(env: &str) -> Self {
+        InMemoryStore {
+            env: env.to_string(),
+            values: Mutex::new(HashMap::new()),
+            sessions: Mutex::new(HashMap::new()),
+            incidents: Mutex::new(Vec::new()),
+            active_share_policy: None,
+            granted_policy: Box::new(|_| true),
+        }
+    }
+
+    /// Overrides the answer of [Db::has_active_share] given the customer id
+    pub fn with_active_share_policy(
+        mut self,
+        policy: impl Fn(&str) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.active_share_policy = Some(Box::new(policy));
+        self
+    }
+
+    /// Overrides the answer of [TxAuthorization::granted]
+    pub fn with_granted_policy(
+        mut self,
+        policy: impl Fn(&TxAuthorizationRequest) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.granted_policy = Box::new(policy);
+        self
+    }
+
+    /// The json of the incidents recorded as [IncidentSink]
+    pub fn incidents(&self) -> Vec<String> {
+        self.incidents
+            .lock()
+            .map(|incidents| incidents.clone())
+            .unwrap_or_default()
+    }
+
+    fn to_key(&self, key: &DbIndex, table_name: &dyn MPCStruct) -> (String, String, String) {
+        (
+            key.customerId.clone(),
+            key.id.clone(),
+            table_name.to_table_name(&self.env),
+        )
+    }
+}
+
+#[async_trait]
+impl Db for InMemoryStore {
+    async fn insert(
+        &self,
+        key: &DbIndex,
+        table_name: &dyn MPCStruct,
+        value: &dyn Value,
+    ) -> Result<(), DatabaseError> {
+        let value = Zeroizing::new(
+            serde_json::to_string(value).or(Err(DatabaseError::InsertError(-1)))?,
+        );
+        self.values
+            .lock()
+            .or(Err(DatabaseError::InsertError(-1)))?
+            .insert(self.to_key(key, table_name), value);
+        Ok(())
+    }
+
+    async fn get(
+        &self,
+        key: &DbIndex,
+        table_name: &dyn MPCStruct,
+    ) -> Result<Option<Box<dyn Value>>, DatabaseError> {
+        let values = self.values.lock().or(Err(DatabaseError::ReadError(-1)))?;
+        match values.get(&self.to_key(key, table_name)) {
+            None => Ok(None),
+            Some(value) => Ok(Some(
+                serde_json::from_str(value.as_str()).or(Err(DatabaseError::ReadError(-1)))?,
+            )),
+        }
+    }
+
+    async fn delete(&self, key: &DbIndex, table_name: &dyn MPCStruct) -> Result<(), DatabaseError> {
+        self.values
+            .lock()
+            .or(Err(DatabaseError::DeleteError(-1)))?
+            .remove(&self.to_key(key, table_name));
+        Ok(())
+    }
+
+    async fn compare_and_swap(
+        &self,
+        key: &DbIndex,
+        table_name: &dyn MPCStruct,
+        expected: Option<&dyn Value>,
+        value: &dyn Value,
+    ) -> Result<bool, DatabaseError> {
+        let expected = expected
+            .map(serde_json::to_string)
+            .transpose()
+            .or(Err(DatabaseError::InsertError(-1)))?
+            .map(Zeroizing::new);
+        let value = Zeroizing::new(
+            serde_json::to_string(value).or(Err(DatabaseError::InsertError(-1)))?,
+        );
+        let mut values = self.values.lock().or(Err(DatabaseError::InsertError(-1)))?;
+        let key = self.to_key(key, table_name);
+        if values.get(&key) != expected.as_ref() {
+            return Ok(false);
+        }
+        values.insert(key, value);
+        Ok(true)
+    }
+
+    async fn keys(&self, table_name: &dyn MPCStruct) -> Result<Vec<DbIndex>, DatabaseError> {
+        let table = table_name.to_table_name(&self.env);
+        let values = self.values.lock().or(Err(DatabaseError::ReadError(-1)))?;
+        Ok(values
+            .keys()
+            .filter(|(_, _, name)| *name == table)
+            .map(|(customer_id, id, _)| DbIndex {
+                customerId: customer_id.clone(),
+                id: id.clone(),
+            })
+            .collect())
+    }
+
+    async fn has_active_share(&self, customerId: &str) -> Result<bool, String> {
+        if let Some(policy) = &self.active_share_policy {
+            return Ok(policy(customerId));
+        }
+        let table = EcdsaStruct::Party1MasterKey.to_table_name(&self.env);
+        let values = self
+            .values
+            .lock()
+            .or(Err("Failed to lock the in-memory store".to_string()))?;
+        Ok(values
+            .keys()
+            .any(|(customer_id, _, table_name)| customer_id == customerId && *table_name == table))
+    }
+}
+
+#[async_trait]
+impl SessionStore for InMemoryStore {
+    async fn put(
+        &self,
+        key: &DbIndex,
+        table_name: &dyn MPCStruct,
+        value: &dyn Value,
+        ttl: Duration,
+    ) -> Result<(), DatabaseError> {
+        let value = Zeroizing::new(
+            serde_json::to_string(value).or(Err(DatabaseError::InsertError(-1)))?,
+        );
+        let now = Instant::now();
+        let mut sessions = self
+            .sessions
+            .lock()
+            .or(Err(DatabaseError::InsertError(-1)))?;
+        sessions.retain(|_, (expiry, _)| *expiry > now);
+        sessions.insert(self.to_key(key, table_name), (now + ttl, value));
+        Ok(())
+    }
+
+    async fn take(
+        &self,
+        key: &DbIndex,
+        table_name: &dyn MPCStruct,
+    ) -> Result<Option<Box<dyn Value>>, DatabaseError> {
+        let value = self
+            .sessions
+            .lock()
+            .or(Err(DatabaseError::ReadError(-1)))?
+            .remove(&self.to_key(key, table_name));
+        match value {
+            Some((expiry, value)) if expiry > Instant::now() => Ok(Some(
+                serde_json::from_str(value.as_str()).or(Err(DatabaseError::ReadError(-1)))?,
+            )),
+            _ => Ok(None),
+        }
+    }
+}
+
+#[async_trait]
+impl IncidentSink for InMemoryStore {
+    async fn record(&self, incident: &SignatureIncident<'_>) -> Result<(), DatabaseError> {
+        let incident = serde_json::to_string(incident).or(Err(DatabaseError::InsertError(-1)))?;
+        self.incidents
+            .lock()
+            .or(Err(DatabaseError::InsertError(-1)))?
+            .push(incident);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl TxAuthorization for InMemoryStore {
+    async fn granted(
+        &self,
+        request: &TxAuthorizationRequest<'_>,
+    ) -> Result<TxDecision, DatabaseError> {
+        if (self.granted_policy)(request) {
+            Ok(TxDecision::Granted)
+        } else {
+            Ok(TxDecision::Denied("Denied by the in-memory policy".to_string()))
+        }
+    }
+}
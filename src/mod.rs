@@ -3,4 +3,19 @@ pub mod traits;
 pub mod types;
 pub mod keygen;
 pub mod sign;
-pub mod routes;
\ No newline at end of file
+pub mod hd;
+pub mod rotate;
+pub mod routes;
+#[cfg(feature = "rocksdb")]
+pub mod rocksdb_store;
+#[cfg(feature = "rusoto_dynamodb")]
+pub mod dynamodb_store;
+pub mod redis_store;
+pub mod memory_store;
+pub mod encrypted_store;
+pub mod cleanup;
+pub mod policy;
+#[cfg(feature = "test-utils")]
+pub mod test_utils;
+pub mod settings;
+pub mod telemetry;
\ No newline at end of file
@@ -0,0 +1,306 @@
+//! A declarative [TxAuthorization] policy engine. Policies are loaded from a TOML or JSON file, the format follows
+//! the extension:
+//! ```toml
+//! # applies to every customer without a policy of their own
+//! [default]
+//! daily_limit = 100000000
+//! allowed_hours = { start = 8, end = 20 }
+//! velocity = { max_txs = 10, window_secs = 3600 }
+//!
+//! [[customers]]
+//! customer_id = "a1b2c3"
+//! daily_limit = 500000000
+//! allowed_destinations = ["bc1qxy2kgdygjrsqtzq2n0yrf2493p83kkfjhx0wlh"]
+//! ```
+//! Every rule is optional. Rules on the amount or the destination require the client to send the matching
+//! [TxMetadata] with the second message of Sign, a tx without it is denied. The metadata is declared by the client and
+//! is not bound to the signed message, which is only a hash, so these rules are advisory: a client can declare any
+//! amount or destination. Verify the metadata against the tx in a trusted layer in front of the engine to enforce them.
+//!
+//! A granted tx is reserved towards spend and velocity in the same step as it is checked, see [PolicyEngine::reserve],
+//! so that concurrent sign requests can not exceed the limits together. It is released if its signature is not
+//! produced. Reservations are kept in memory, so the limits are per engine instance and reset on restart.
+//!
+//! Hours are `0` to `23` and a window must not start and end at the same hour, [PolicyEngine::new] rejects the policies
+//! otherwise.
+use crate::traits::TxAuthorization;
+use crate::types::{DatabaseError, TxAuthorizationRequest, TxDecision};
+
+use two_party_ecdsa::BigInt;
+
+use chrono::{DateTime, Duration, Timelike, Utc};
+use config::{Config, ConfigError, File};
+use rocket::async_trait;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// The rules applied to the txs of a customer
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Policy {
+    /// The maximum total amount over the last 24 hours
+    pub daily_limit: Option<u64>,
+    pub allowed_destinations: Option<Vec<String>>,
+    pub allowed_hours: Option<HourWindow>,
+    pub velocity: Option<VelocityLimit>,
+}
+
+/// A window of UTC hours `[start, end)`. A window with `start > end` wraps around midnight
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct HourWindow {
+    pub start: u32,
+    pub end: u32,
+}
+
+impl HourWindow {
+    /// Both hours within a day, and `start != end`, which would be an empty or a full day depending on the reader
+    pub fn is_valid(&self) -> bool {
+        self.start < 24 && self.end < 24 && self.start != self.end
+    }
+
+    pub fn contains(&self, hour: u32) -> bool {
+        if self.start <= self.end {
+            self.start <= hour && hour < self.end
+        } else {
+            hour >= self.start || hour < self.end
+        }
+    }
+}
+
+/// At most `max_txs` txs within any `window_secs`
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct VelocityLimit {
+    pub max_txs: usize,
+    pub window_secs: i64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CustomerPolicy {
+    pub customer_id: String,
+    #[serde(flatten)]
+    pub policy: Policy,
+}
+
+/// The content of a policy file
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct PolicySet {
+    #[serde(default)]
+    pub default: Policy,
+    #[serde(default)]
+    pub customers: Vec<CustomerPolicy>,
+}
+
+/// A granted tx, counted towards the limits until it is released
+#[derive(Debug)]
+struct Reservation {
+    time: DateTime<Utc>,
+    amount: u64,
+    key_id: String,
+    message: BigInt,
+}
+
+/// Evaluates a [PolicySet] on every second round of Sign
+pub struct PolicyEngine {
+    default: Policy,
+    customers: HashMap<String, Policy>,
+    /// customerId -> the reservations of the granted txs
+    history: Mutex<HashMap<String, Vec<Reservation>>>,
+}
+
+impl PolicyEngine {
+    /// Fails on an invalid [HourWindow]
+    pub fn new(policies: PolicySet) -> Result<Self, ConfigError> {
+        let policies_with_ids = std::iter::once(("default", &policies.default)).chain(
+            policies
+                .customers
+                .iter()
+                .map(|customer| (customer.customer_id.as_str(), &customer.policy)),
+        );
+        for (customer_id, policy) in policies_with_ids {
+            if let Some(window) = policy.allowed_hours.filter(|window| !window.is_valid()) {
+                return Err(ConfigError::Message(format!(
+                    "Invalid allowed_hours {}-{} of {}, hours are 0 to 23 and must differ",
+                    window.start, window.end, customer_id
+                )));
+            }
+        }
+        Ok(PolicyEngine {
+            default: policies.default,
+            customers: policies
+                .customers
+                .into_iter()
+                .map(|customer| (customer.customer_id, customer.policy))
+                .collect(),
+            history: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Loads the policies from a `.toml` or `.json` file
+    pub fn from_file(path: &str) -> Result<Self, ConfigError> {
+        let policies: PolicySet = Config::builder()
+            .add_source(File::with_name(path))
+            .build()?
+            .try_deserialize()?;
+        Self::new(policies)
+    }
+
+    /// The policy of the customer, or the default one
+    pub fn policy(&self, customer_id: &str) -> &Policy {
+        self.customers.get(customer_id).unwrap_or(&self.default)
+    }
+
+    /// Evaluates the request as if it was made at `now`, without counting it towards the spend and velocity limits
+    pub fn evaluate(
+        &self,
+        request: &TxAuthorizationRequest<'_>,
+        now: DateTime<Utc>,
+    ) -> Result<TxDecision, DatabaseError> {
+        let mut history = self.history.lock().or(Err(DatabaseError::ReadError(-1)))?;
+        let txs = history.entry(request.customer_id.to_string()).or_default();
+        self.check(request, txs, now)
+    }
+
+    /// Evaluates the request as if it was made at `now` and, if it is granted, reserves it towards the spend and
+    /// velocity limits. Both happen under one lock, so that concurrent requests see each other's reservations
+    pub fn reserve(
+        &self,
+        request: &TxAuthorizationRequest<'_>,
+        now: DateTime<Utc>,
+    ) -> Result<TxDecision, DatabaseError> {
+        let mut history = self
+            .history
+            .lock()
+            .or(Err(DatabaseError::InsertError(-1)))?;
+        let txs = history.entry(request.customer_id.to_string()).or_default();
+        let decision = self.check(request, txs, now)?;
+        if decision == TxDecision::Granted {
+            txs.push(Reservation {
+                time: now,
+                amount: request
+                    .metadata
+                    .and_then(|metadata| metadata.amount)
+                    .unwrap_or(0),
+                key_id: request.key_id.to_string(),
+                message: request.message.clone(),
+            });
+        }
+        Ok(decision)
+    }
+
+    /// Gives back the latest reservation of the request, once its signature failed
+    pub fn release(&self, request: &TxAuthorizationRequest<'_>) -> Result<(), DatabaseError> {
+        let amount = request
+            .metadata
+            .and_then(|metadata| metadata.amount)
+            .unwrap_or(0);
+        let mut history = self
+            .history
+            .lock()
+            .or(Err(DatabaseError::DeleteError(-1)))?;
+        if let Some(txs) = history.get_mut(request.customer_id) {
+            if let Some(index) = txs.iter().rposition(|tx| {
+                tx.key_id == request.key_id && tx.message == *request.message && tx.amount == amount
+            }) {
+                txs.remove(index);
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the request against the policy of its customer and the reservations `txs`, dropping those too old to
+    /// count towards any limit
+    fn check(
+        &self,
+        request: &TxAuthorizationRequest<'_>,
+        txs: &mut Vec<Reservation>,
+        now: DateTime<Utc>,
+    ) -> Result<TxDecision, DatabaseError> {
+        let policy = self.policy(request.customer_id);
+        let amount = request.metadata.and_then(|metadata| metadata.amount);
+        let destination = request
+            .metadata
+            .and_then(|metadata| metadata.destination.as_deref());
+
+        if let Some(window) = &policy.allowed_hours {
+            if !window.contains(now.hour()) {
+                return Ok(TxDecision::Denied(format!(
+                    "Tx outside of the allowed hours {}:00-{}:00 UTC",
+                    window.start, window.end
+                )));
+            }
+        }
+
+        if let Some(allowed) = &policy.allowed_destinations {
+            match destination {
+                None => {
+                    return Ok(TxDecision::Denied(
+                        "Missing tx destination, required by the destination allow-list"
+                            .to_string(),
+                    ))
+                }
+                Some(destination) if !allowed.iter().any(|a| a == destination) => {
+                    return Ok(TxDecision::Denied(format!(
+                        "Destination {} is not allowed",
+                        destination
+                    )))
+                }
+                Some(_) => {}
+            }
+        }
+
+        let retention = policy.velocity.map_or(Duration::days(1), |velocity| {
+            Duration::days(1).max(Duration::seconds(velocity.window_secs))
+        });
+        txs.retain(|tx| tx.time > now - retention);
+
+        if let Some(velocity) = &policy.velocity {
+            let since = now - Duration::seconds(velocity.window_secs);
+            let count = txs.iter().filter(|tx| tx.time > since).count();
+            if count >= velocity.max_txs {
+                return Ok(TxDecision::Denied(format!(
+                    "Velocity limit of {} txs per {} seconds reached",
+                    velocity.max_txs, velocity.window_secs
+                )));
+            }
+        }
+
+        if let Some(limit) = policy.daily_limit {
+            let amount = match amount {
+                None => {
+                    return Ok(TxDecision::Denied(
+                        "Missing tx amount, required by the daily limit".to_string(),
+                    ))
+                }
+                Some(amount) => amount,
+            };
+            let since = now - Duration::days(1);
+            let spent: u64 = txs
+                .iter()
+                .filter(|tx| tx.time > since)
+                .map(|tx| tx.amount)
+                .sum();
+            if spent.saturating_add(amount) > limit {
+                return Ok(TxDecision::Denied(format!(
+                    "Daily limit of {} exceeded, already spent {}",
+                    limit, spent
+                )));
+            }
+        }
+
+        Ok(TxDecision::Granted)
+    }
+}
+
+#[async_trait]
+impl TxAuthorization for PolicyEngine {
+    async fn granted(
+        &self,
+        request: &TxAuthorizationRequest<'_>,
+    ) -> Result<TxDecision, DatabaseError> {
+        self.reserve(request, Utc::now())
+    }
+
+    async fn released(&self, request: &TxAuthorizationRequest<'_>) -> Result<(), DatabaseError> {
+        self.release(request)
+    }
+}
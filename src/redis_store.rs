@@ -0,0 +1,440 @@
+//! A ready to use [Db] implementation over Redis, either a single node or a cluster. Values are stored as typetag
+//! serialized json under `gotham:{table}:{len}:{customer_id}{id}` keys, the customer id being prefixed with its length
+//! so that no two customers or ids share a key, and the keys of every table in a `gotham:index:{table}` set for
+//! [Db::keys]. It
+//! is also a [SessionStore] for the cross-session sign rounds, which relies on `GETDEL` and requires redis 6.2 or
+//! later. Commands run on one async connection, opened on first use and shared by all requests.
+use crate::settings::EngineConfig;
+use crate::traits::{Db, MPCStruct, SessionStore, TxAuthorization};
+use crate::types::{DatabaseError, DbIndex, EcdsaStruct, TxAuthorizationRequest, TxDecision};
+
+use two_party_ecdsa::party_one::{Converter, Value};
+
+use redis::aio::{ConnectionLike, ConnectionManager};
+use redis::cluster::ClusterClient;
+use redis::cluster_async::ClusterConnection;
+use redis::{Client, Cmd, Pipeline, RedisFuture};
+use rocket::async_trait;
+use std::time::Duration;
+use tokio::sync::OnceCell;
+use tracing::error;
+use zeroize::Zeroizing;
+
+/// Sets KEYS[1] to ARGV[2] if it still holds ARGV[1], an empty ARGV[1] standing for a missing key
+const COMPARE_AND_SWAP: &str = r#"
+local current = redis.call('GET', KEYS[1])
+if (current == false and ARGV[1] == '') or current == ARGV[1] then
+    redis.call('SET', KEYS[1], ARGV[2])
+    return 1
+end
+return 0
+"#;
+
+/// How many members of an index set are read per `SSCAN`
+const SCAN_COUNT: usize = 1000;
+
+enum RedisClient {
+    Single(Client),
+    Cluster(ClusterClient),
+}
+
+/// A multiplexed connection to a node or a cluster. Clones share the underlying connection
+#[derive(Clone)]
+enum RedisConnection {
+    Single(ConnectionManager),
+    Cluster(ClusterConnection),
+}
+
+impl ConnectionLike for RedisConnection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, redis::Value> {
+        match self {
+            RedisConnection::Single(con) => con.req_packed_command(cmd),
+            RedisConnection::Cluster(con) => con.req_packed_command(cmd),
+        }
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<redis::Value>> {
+        match self {
+            RedisConnection::Single(con) => con.req_packed_commands(cmd, offset, count),
+            RedisConnection::Cluster(con) => con.req_packed_commands(cmd, offset, count),
+        }
+    }
+
+    fn get_db(&self) -> i64 {
+        match self {
+            RedisConnection::Single(con) => con.get_db(),
+            RedisConnection::Cluster(con) => con.get_db(),
+        }
+    }
+}
+
+/// Redis backed storage for the state of KeyGen and Sign
+pub struct RedisStore {
+    client: RedisClient,
+    connection: OnceCell<RedisConnection>,
+}
+
+impl RedisStore {
+    /// Connects to a single redis node, e.g. `redis://127.0.0.1:6379`
+    pub fn new(url: &str) -> Result<Self, DatabaseError> {
+        let client = Client::open(url).map_err(|e| {
+            error!("Failed to open redis client at {}: {}", url, e);
+            DatabaseError::ConnectionError(-1)
+        })?;
+        Ok(RedisStore {
+            client: RedisClient::Single(client),
+            connection: OnceCell::new(),
+        })
+    }
+
+    /// Connects to a redis cluster given some of its nodes
+    pub fn new_cluster(nodes: Vec<String>) -> Result<Self, DatabaseError> {
+        let client = ClusterClient::new(nodes).map_err(|e| {
+            error!("Failed to open redis cluster client: {}", e);
+            DatabaseError::ConnectionError(-1)
+        })?;
+        Ok(RedisStore {
+            client: RedisClient::Cluster(client),
+            connection: OnceCell::new(),
+        })
+    }
+
+    /// Connects to [EngineConfig::redis_url], the same node used by the cross-session Sign rounds
+    pub fn from_config(config: &EngineConfig) -> Result<Self, DatabaseError> {
+        let redis_url = config
+            .redis_url
+            .as_deref()
+            .ok_or(DatabaseError::ConfigError(-1))?;
+        Self::new(redis_url)
+    }
+
+    /// The shared connection, opened on first use. It reconnects by itself once opened
+    async fn connection(&self) -> Result<RedisConnection, DatabaseError> {
+        let connection = self
+            .connection
+            .get_or_try_init(|| async {
+                match &self.client {
+                    RedisClient::Single(client) => ConnectionManager::new(client.clone())
+                        .await
+                        .map(RedisConnection::Single),
+                    RedisClient::Cluster(client) => client
+                        .get_async_connection()
+                        .await
+                        .map(RedisConnection::Cluster),
+                }
+            })
+            .await
+            .map_err(|e| {
+                error!("Failed to connect to redis: {}", e);
+                DatabaseError::ConnectionError(-1)
+            })?;
+        Ok(connection.clone())
+    }
+
+    /// `{len}:{customer_id}{rest}`, which tells the customer id apart from `rest` whatever both hold
+    fn customer_prefixed(customer_id: &str, rest: &str) -> String {
+        format!("{}:{}{}", customer_id.len(), customer_id, rest)
+    }
+
+    /// The key of a value. Table names hold no `:`, so that the keys of tables, customers and ids are all distinct
+    fn value_key(key: &DbIndex, table_name: &dyn MPCStruct) -> String {
+        format!(
+            "gotham:{}:{}",
+            table_name.to_string(),
+            Self::customer_prefixed(&key.customerId, &key.id)
+        )
+    }
+
+    fn active_shares_key(customer_id: &str) -> String {
+        format!("gotham:active:{}", Self::customer_prefixed(customer_id, ""))
+    }
+
+    fn granted_key(customer_id: &str, message: &str) -> String {
+        format!("gotham:granted:{}", Self::customer_prefixed(customer_id, message))
+    }
+
+    fn index_key(table_name: &dyn MPCStruct) -> String {
+        format!("gotham:index:{}", table_name.to_string())
+    }
+
+    /// The member of `key` in the index set of its table. The json keeps the customer id and id apart whatever they hold
+    fn index_member(key: &DbIndex) -> Result<String, DatabaseError> {
+        serde_json::to_string(key).or(Err(DatabaseError::InsertError(-1)))
+    }
+}
+
+#[async_trait]
+impl Db for RedisStore {
+    async fn insert(
+        &self,
+        key: &DbIndex,
+        table_name: &dyn MPCStruct,
+        value: &dyn Value,
+    ) -> Result<(), DatabaseError> {
+        let identifier = Self::value_key(key, table_name);
+        let value = Zeroizing::new(
+            serde_json::to_string(value).or(Err(DatabaseError::InsertError(-1)))?,
+        );
+        let is_master_key = table_name.to_string() == EcdsaStruct::Party1MasterKey.to_string();
+
+        let mut con = self.connection().await?;
+        let mut res = redis::cmd("SET")
+            .arg(&identifier)
+            .arg(value.as_str())
+            .query_async::<_, ()>(&mut con)
+            .await;
+        if res.is_ok() {
+            res = redis::cmd("SADD")
+                .arg(Self::index_key(table_name))
+                .arg(Self::index_member(key)?)
+                .query_async::<_, ()>(&mut con)
+                .await;
+        }
+        if res.is_ok() && is_master_key {
+            res = redis::cmd("SADD")
+                .arg(Self::active_shares_key(&key.customerId))
+                .arg(&key.id)
+                .query_async::<_, ()>(&mut con)
+                .await;
+        }
+        res.map_err(|e| {
+            error!("Failed to insert {} into redis: {}", identifier, e);
+            DatabaseError::InsertError(-1)
+        })
+    }
+
+    async fn get(
+        &self,
+        key: &DbIndex,
+        table_name: &dyn MPCStruct,
+    ) -> Result<Option<Box<dyn Value>>, DatabaseError> {
+        let identifier = Self::value_key(key, table_name);
+        let mut con = self.connection().await?;
+        let value: Option<String> = redis::cmd("GET")
+            .arg(&identifier)
+            .query_async(&mut con)
+            .await
+            .map_err(|e| {
+                error!("Failed to get {} from redis: {}", identifier, e);
+                DatabaseError::ReadError(-1)
+            })?;
+
+        match value {
+            None => Ok(None),
+            Some(value) => {
+                let value = Zeroizing::new(value);
+                let value: Box<dyn Value> = serde_json::from_str(&value).map_err(|e| {
+                    error!("Failed to deserialize {}: {}", identifier, e);
+                    DatabaseError::ReadError(-1)
+                })?;
+                Ok(Some(value))
+            }
+        }
+    }
+
+    async fn delete(&self, key: &DbIndex, table_name: &dyn MPCStruct) -> Result<(), DatabaseError> {
+        let identifier = Self::value_key(key, table_name);
+        let is_master_key = table_name.to_string() == EcdsaStruct::Party1MasterKey.to_string();
+
+        let mut con = self.connection().await?;
+        let mut res = redis::cmd("DEL")
+            .arg(&identifier)
+            .query_async::<_, ()>(&mut con)
+            .await;
+        if res.is_ok() {
+            res = redis::cmd("SREM")
+                .arg(Self::index_key(table_name))
+                .arg(Self::index_member(key)?)
+                .query_async::<_, ()>(&mut con)
+                .await;
+        }
+        if res.is_ok() && is_master_key {
+            res = redis::cmd("SREM")
+                .arg(Self::active_shares_key(&key.customerId))
+                .arg(&key.id)
+                .query_async::<_, ()>(&mut con)
+                .await;
+        }
+        res.map_err(|e| {
+            error!("Failed to delete {} from redis: {}", identifier, e);
+            DatabaseError::DeleteError(-1)
+        })
+    }
+
+    async fn compare_and_swap(
+        &self,
+        key: &DbIndex,
+        table_name: &dyn MPCStruct,
+        expected: Option<&dyn Value>,
+        value: &dyn Value,
+    ) -> Result<bool, DatabaseError> {
+        let identifier = Self::value_key(key, table_name);
+        let expected = match expected {
+            None => String::new(),
+            Some(expected) => {
+                serde_json::to_string(expected).or(Err(DatabaseError::InsertError(-1)))?
+            }
+        };
+        let value = Zeroizing::new(
+            serde_json::to_string(value).or(Err(DatabaseError::InsertError(-1)))?,
+        );
+
+        let mut con = self.connection().await?;
+        let mut res = redis::cmd("EVAL")
+            .arg(COMPARE_AND_SWAP)
+            .arg(1)
+            .arg(&identifier)
+            .arg(&expected)
+            .arg(value.as_str())
+            .query_async::<_, bool>(&mut con)
+            .await;
+        if let Ok(true) = res {
+            res = redis::cmd("SADD")
+                .arg(Self::index_key(table_name))
+                .arg(Self::index_member(key)?)
+                .query_async::<_, ()>(&mut con)
+                .await
+                .map(|_| true);
+        }
+        res.map_err(|e| {
+            error!("Failed to swap {} in redis: {}", identifier, e);
+            DatabaseError::InsertError(-1)
+        })
+    }
+
+    /// Reads the index set of the table, which holds a single key and so works on a cluster as well
+    async fn keys(&self, table_name: &dyn MPCStruct) -> Result<Vec<DbIndex>, DatabaseError> {
+        let index_key = Self::index_key(table_name);
+        let mut con = self.connection().await?;
+
+        let mut keys = vec![];
+        let mut cursor = 0u64;
+        loop {
+            let (next, batch): (u64, Vec<String>) = redis::cmd("SSCAN")
+                .arg(&index_key)
+                .arg(cursor)
+                .arg("COUNT")
+                .arg(SCAN_COUNT)
+                .query_async(&mut con)
+                .await
+                .map_err(|e| {
+                    error!("Failed to scan redis for {}: {}", index_key, e);
+                    DatabaseError::ReadError(-1)
+                })?;
+            for member in batch {
+                let key: DbIndex = serde_json::from_str(&member).map_err(|e| {
+                    error!("Failed to deserialize {} of {}: {}", member, index_key, e);
+                    DatabaseError::ReadError(-1)
+                })?;
+                keys.push(key);
+            }
+            if next == 0 {
+                return Ok(keys);
+            }
+            cursor = next;
+        }
+    }
+
+    async fn has_active_share(&self, customerId: &str) -> Result<bool, String> {
+        let mut con = self
+            .connection()
+            .await
+            .map_err(|e| format!("Failed to connect to redis: {}", e))?;
+        let shares: usize = redis::cmd("SCARD")
+            .arg(Self::active_shares_key(customerId))
+            .query_async(&mut con)
+            .await
+            .map_err(|e| format!("Failed to search redis for active shares: {}", e))?;
+        Ok(shares > 0)
+    }
+}
+
+#[async_trait]
+impl SessionStore for RedisStore {
+    async fn put(
+        &self,
+        key: &DbIndex,
+        table_name: &dyn MPCStruct,
+        value: &dyn Value,
+        ttl: Duration,
+    ) -> Result<(), DatabaseError> {
+        let identifier = Self::value_key(key, table_name);
+        let value = Zeroizing::new(
+            serde_json::to_string(value).or(Err(DatabaseError::InsertError(-1)))?,
+        );
+        let mut con = self.connection().await?;
+        redis::cmd("SET")
+            .arg(&identifier)
+            .arg(value.as_str())
+            .arg("EX")
+            .arg(ttl.as_secs().max(1))
+            .query_async::<_, ()>(&mut con)
+            .await
+            .map_err(|e| {
+                error!("Failed to insert {} into redis: {}", identifier, e);
+                DatabaseError::InsertError(-1)
+            })
+    }
+
+    async fn take(
+        &self,
+        key: &DbIndex,
+        table_name: &dyn MPCStruct,
+    ) -> Result<Option<Box<dyn Value>>, DatabaseError> {
+        let identifier = Self::value_key(key, table_name);
+        let mut con = self.connection().await?;
+        let value: Option<String> = redis::cmd("GETDEL")
+            .arg(&identifier)
+            .query_async(&mut con)
+            .await
+            .map_err(|e| {
+                error!("Failed to take {} from redis: {}", identifier, e);
+                DatabaseError::ReadError(-1)
+            })?;
+
+        match value {
+            None => Ok(None),
+            Some(value) => {
+                let value = Zeroizing::new(value);
+                let value: Box<dyn Value> = serde_json::from_str(&value).map_err(|e| {
+                    error!("Failed to deserialize {}: {}", identifier, e);
+                    DatabaseError::ReadError(-1)
+                })?;
+                Ok(Some(value))
+            }
+        }
+    }
+}
+
+/// The redis-pps flow: a tx is granted once the policy service has written the
+/// `gotham:granted:{len}:{customer_id}{message}` key, `len` being the length of the customer id and `message` in hex
+#[async_trait]
+impl TxAuthorization for RedisStore {
+    async fn granted(
+        &self,
+        request: &TxAuthorizationRequest<'_>,
+    ) -> Result<TxDecision, DatabaseError> {
+        let key = Self::granted_key(request.customer_id, &request.message.to_hex());
+        let mut con = self.connection().await?;
+        let granted: bool = redis::cmd("EXISTS")
+            .arg(&key)
+            .query_async(&mut con)
+            .await
+            .map_err(|e| {
+                error!("Failed to read tx authorization {} from redis: {}", key, e);
+                DatabaseError::ReadError(-1)
+            })?;
+        if granted {
+            Ok(TxDecision::Granted)
+        } else {
+            Ok(TxDecision::Denied(
+                "Unauthorized transaction from redis-pps".to_string(),
+            ))
+        }
+    }
+}
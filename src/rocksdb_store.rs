@@ -0,0 +1,224 @@
+//! A ready to use [Db] implementation over RocksDB. Every table derived from [MPCStruct] lives in its own
+//! column family and values are stored as typetag serialized json under a key derived from [DbIndex]. The key is
+//! `{len}:{customerId}{id}`, the length prefix keeping apart customers whose ids prefix one another.
+use crate::traits::{Db, MPCStruct};
+use crate::types::{DatabaseError, DbIndex, EcdsaStruct};
+
+use two_party_ecdsa::party_one::Value;
+
+use rocket::async_trait;
+use rocksdb::{ColumnFamilyDescriptor, DBWithThreadMode, IteratorMode, MultiThreaded, Options};
+use std::path::Path;
+use tokio::sync::Mutex;
+use tracing::error;
+use zeroize::Zeroizing;
+
+/// RocksDB backed storage for the state of KeyGen and Sign
+pub struct RocksDbStore {
+    db: DBWithThreadMode<MultiThreaded>,
+    env: String,
+    /// Serializes [Db::compare_and_swap], the database is owned by this process
+    cas_lock: Mutex<()>,
+}
+
+impl RocksDbStore {
+    /// Opens (or creates) the database at `path`. A column family is created for each [EcdsaStruct]
+    /// named after [MPCStruct::to_table_name] for the given `env`, next to any column family already on disk.
+    pub fn open<P: AsRef<Path>>(path: P, env: &str) -> Result<Self, DatabaseError> {
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        opts.create_missing_column_families(true);
+
+        let mut cf_names: Vec<String> = EcdsaStruct::ALL
+            .iter()
+            .map(|table| table.to_table_name(env))
+            .collect();
+        if let Ok(existing) = DBWithThreadMode::<MultiThreaded>::list_cf(&opts, &path) {
+            for name in existing {
+                if !cf_names.contains(&name) {
+                    cf_names.push(name);
+                }
+            }
+        }
+        let cfs = cf_names
+            .into_iter()
+            .map(|name| ColumnFamilyDescriptor::new(name, Options::default()));
+
+        let db = DBWithThreadMode::<MultiThreaded>::open_cf_descriptors(&opts, path, cfs)
+            .map_err(|e| {
+                error!("Failed to open rocksdb: {}", e);
+                DatabaseError::ConnectionError(e.kind() as i32)
+            })?;
+
+        Ok(RocksDbStore {
+            db,
+            env: env.to_string(),
+            cas_lock: Mutex::new(()),
+        })
+    }
+
+    fn to_key(key: &DbIndex) -> String {
+        format!("{}{}", Self::customer_prefix(&key.customerId), key.id)
+    }
+
+    /// The prefix of the keys of a customer, and only of that customer
+    fn customer_prefix(customer_id: &str) -> String {
+        format!("{}:{}", customer_id.len(), customer_id)
+    }
+
+    /// The inverse of [Self::to_key]
+    fn from_key(key: &str) -> Option<DbIndex> {
+        let (len, rest) = key.split_once(':')?;
+        let len: usize = len.parse().ok()?;
+        if !rest.is_char_boundary(len) {
+            return None;
+        }
+        let (customer_id, id) = rest.split_at(len);
+        Some(DbIndex {
+            customerId: customer_id.to_string(),
+            id: id.to_string(),
+        })
+    }
+}
+
+#[async_trait]
+impl Db for RocksDbStore {
+    async fn insert(
+        &self,
+        key: &DbIndex,
+        table_name: &dyn MPCStruct,
+        value: &dyn Value,
+    ) -> Result<(), DatabaseError> {
+        let cf_name = table_name.to_table_name(&self.env);
+        if self.db.cf_handle(&cf_name).is_none() {
+            self.db
+                .create_cf(&cf_name, &Options::default())
+                .map_err(|e| {
+                    error!("Failed to create column family {}: {}", cf_name, e);
+                    DatabaseError::TableCreationError(e.kind() as i32)
+                })?;
+        }
+        let cf = self
+            .db
+            .cf_handle(&cf_name)
+            .ok_or(DatabaseError::TableCreationError(-1))?;
+
+        let value = Zeroizing::new(
+            serde_json::to_string(value).or(Err(DatabaseError::InsertError(-1)))?,
+        );
+        self.db
+            .put_cf(&cf, Self::to_key(key), value.as_bytes())
+            .map_err(|e| {
+                error!("Failed to insert into {}: {}", cf_name, e);
+                DatabaseError::InsertError(e.kind() as i32)
+            })
+    }
+
+    async fn get(
+        &self,
+        key: &DbIndex,
+        table_name: &dyn MPCStruct,
+    ) -> Result<Option<Box<dyn Value>>, DatabaseError> {
+        let cf_name = table_name.to_table_name(&self.env);
+        let cf = match self.db.cf_handle(&cf_name) {
+            Some(cf) => cf,
+            None => return Ok(None),
+        };
+
+        let bytes = self.db.get_cf(&cf, Self::to_key(key)).map_err(|e| {
+            error!("Failed to get from {}: {}", cf_name, e);
+            DatabaseError::ReadError(e.kind() as i32)
+        })?;
+
+        match bytes {
+            None => Ok(None),
+            Some(bytes) => {
+                let bytes = Zeroizing::new(bytes);
+                let value: Box<dyn Value> = serde_json::from_slice(&bytes).map_err(|e| {
+                    error!("Failed to deserialize value from {}: {}", cf_name, e);
+                    DatabaseError::ReadError(-1)
+                })?;
+                Ok(Some(value))
+            }
+        }
+    }
+
+    async fn delete(&self, key: &DbIndex, table_name: &dyn MPCStruct) -> Result<(), DatabaseError> {
+        let cf_name = table_name.to_table_name(&self.env);
+        let cf = match self.db.cf_handle(&cf_name) {
+            Some(cf) => cf,
+            None => return Ok(()),
+        };
+        self.db.delete_cf(&cf, Self::to_key(key)).map_err(|e| {
+            error!("Failed to delete from {}: {}", cf_name, e);
+            DatabaseError::DeleteError(e.kind() as i32)
+        })
+    }
+
+    async fn compare_and_swap(
+        &self,
+        key: &DbIndex,
+        table_name: &dyn MPCStruct,
+        expected: Option<&dyn Value>,
+        value: &dyn Value,
+    ) -> Result<bool, DatabaseError> {
+        let expected = expected
+            .map(serde_json::to_vec)
+            .transpose()
+            .or(Err(DatabaseError::InsertError(-1)))?
+            .map(Zeroizing::new);
+        let _guard = self.cas_lock.lock().await;
+
+        let cf_name = table_name.to_table_name(&self.env);
+        let current = match self.db.cf_handle(&cf_name) {
+            None => None,
+            Some(cf) => self
+                .db
+                .get_cf(&cf, Self::to_key(key))
+                .map_err(|e| {
+                    error!("Failed to get from {}: {}", cf_name, e);
+                    DatabaseError::ReadError(e.kind() as i32)
+                })?
+                .map(Zeroizing::new),
+        };
+        if current != expected {
+            return Ok(false);
+        }
+        self.insert(key, table_name, value).await?;
+        Ok(true)
+    }
+
+    async fn keys(&self, table_name: &dyn MPCStruct) -> Result<Vec<DbIndex>, DatabaseError> {
+        let cf_name = table_name.to_table_name(&self.env);
+        let cf = match self.db.cf_handle(&cf_name) {
+            Some(cf) => cf,
+            None => return Ok(vec![]),
+        };
+        let mut keys = vec![];
+        for item in self.db.iterator_cf(&cf, IteratorMode::Start) {
+            let (key, _) = item.map_err(|e| {
+                error!("Failed to scan {}: {}", cf_name, e);
+                DatabaseError::ReadError(e.kind() as i32)
+            })?;
+            if let Some(key) = Self::from_key(&String::from_utf8_lossy(&key)) {
+                keys.push(key);
+            }
+        }
+        Ok(keys)
+    }
+
+    async fn has_active_share(&self, customerId: &str) -> Result<bool, String> {
+        let cf_name = EcdsaStruct::Party1MasterKey.to_table_name(&self.env);
+        let cf = match self.db.cf_handle(&cf_name) {
+            Some(cf) => cf,
+            None => return Ok(false),
+        };
+
+        let prefix = Self::customer_prefix(customerId);
+        match self.db.prefix_iterator_cf(&cf, prefix.as_bytes()).next() {
+            None => Ok(false),
+            Some(Ok((key, _))) => Ok(key.starts_with(prefix.as_bytes())),
+            Some(Err(e)) => Err(format!("Failed to scan {}: {}", cf_name, e)),
+        }
+    }
+}
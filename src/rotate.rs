@@ -0,0 +1,384 @@
+//! Proactive refresh of the key shares. The parties flip a coin and rotate their shares and party one's Paillier
+//! key by the outcome, while the joint public key, and so the addresses of the user, stay the same.
+use crate::cleanup;
+use crate::guarder::Claims;
+use crate::keygen::{downcast, take};
+use crate::sign::check_not_tainted;
+use crate::telemetry::timed_blocking;
+use crate::traits::Db;
+use crate::types::{
+    Alpha, DbIndex, EcdsaStruct, EngineError, RotateCommit, RotateFirstMsg, RotatePDLFirstMsg,
+    RotateRandom, RotationStage, RotationState, SessionLocks,
+};
+
+use two_party_ecdsa::curv::cryptographic_primitives::twoparty::coin_flip_optimal_rounds;
+use two_party_ecdsa::kms::ecdsa::two_party::{party1, MasterKey1};
+use two_party_ecdsa::kms::rotation::two_party::party1::Rotation1;
+use two_party_ecdsa::party_one::{PDLdecommit, Party1Private};
+use two_party_ecdsa::{party_one, party_two, BigInt};
+
+use chrono::Utc;
+use rocket::serde::json::Json;
+use rocket::{async_trait, State};
+use std::sync::Arc;
+use tracing::warn;
+
+#[async_trait]
+pub trait Rotate {
+    ///first round of rotation: party one commits to its share of the coin flip
+    async fn rotate_first(
+        state: &State<Arc<dyn Db>>,
+        locks: &State<SessionLocks>,
+        claim: Claims,
+        id: String,
+    ) -> Result<Json<coin_flip_optimal_rounds::Party1FirstMessage>, EngineError> {
+        let db = state.inner();
+        let key = DbIndex {
+            customerId: claim.sub.to_string(),
+            id: id.clone(),
+        };
+        let _session = locks.lock(&key).await;
+
+        //only keys that completed keygen can be rotated, and a tainted key stays locked out
+        db.get(&key, &EcdsaStruct::Party1MasterKey)
+            .await?
+            .ok_or(EngineError::MissingState(id.to_string()))?;
+        check_not_tainted(db.as_ref(), &key).await?;
+        let rotation = claim_start(db.as_ref(), &key).await?;
+
+        let (party1_coin_flip_first_message, m1, r1) = Rotation1::key_rotate_first_message();
+
+        db.insert(&key, &EcdsaStruct::RotateCommit, &RotateCommit { m1, r1 })
+            .await?;
+        complete_stage(db.as_ref(), &key, &rotation, RotationStage::FirstDone).await?;
+
+        Ok(Json(party1_coin_flip_first_message))
+    }
+
+    ///second round of rotation: party one reveals the coin flip and refreshes its share and Paillier key
+    async fn rotate_second(
+        state: &State<Arc<dyn Db>>,
+        locks: &State<SessionLocks>,
+        claim: Claims,
+        id: String,
+        party2_first_message: Json<coin_flip_optimal_rounds::Party2FirstMessage>,
+    ) -> Result<
+        Json<(
+            coin_flip_optimal_rounds::Party1SecondMessage,
+            party1::RotationParty1Message1,
+        )>,
+        EngineError,
+    > {
+        let db = state.inner();
+        let key = DbIndex {
+            customerId: claim.sub.to_string(),
+            id: id.clone(),
+        };
+        let _session = locks.lock(&key).await;
+        let rotation = claim_stage(db.as_ref(), &key, RotationStage::FirstDone, "second").await?;
+
+        let master_key = db
+            .get(&key, &EcdsaStruct::Party1MasterKey)
+            .await?
+            .ok_or(EngineError::MissingState(id.to_string()))?;
+        let commit = db
+            .get(&key, &EcdsaStruct::RotateCommit)
+            .await?
+            .ok_or(EngineError::MissingState(id.to_string()))?;
+        let commit = downcast::<RotateCommit>(commit.as_ref(), &id)?;
+
+        let (party1_second_message, random1) = Rotation1::key_rotate_second_message(
+            &party2_first_message.0,
+            &commit.m1,
+            &commit.r1,
+        );
+        let master_key = take::<MasterKey1>(master_key, &id)?;
+        let (rotation_party_one_first_message, party_one_private_new, random1) =
+            timed_blocking("paillier_keygen", move || {
+                let (message, party_one_private_new) = master_key.rotation_first_message(&random1);
+                (message, party_one_private_new, random1)
+            })
+            .await?;
+
+        db.insert(&key, &EcdsaStruct::RotateRandom, &RotateRandom { value: random1 })
+            .await?;
+        let rotation_party_one_first_message = RotateFirstMsg {
+            value: rotation_party_one_first_message,
+        };
+        db.insert(&key, &EcdsaStruct::RotateFirstMsg, &rotation_party_one_first_message)
+            .await?;
+        db.insert(&key, &EcdsaStruct::RotatePrivateNew, &party_one_private_new)
+            .await?;
+        complete_stage(db.as_ref(), &key, &rotation, RotationStage::SecondDone).await?;
+
+        Ok(Json((party1_second_message, rotation_party_one_first_message.value)))
+    }
+
+    ///third round of rotation: the PDL proof of the refreshed Paillier key
+    async fn rotate_third(
+        state: &State<Arc<dyn Db>>,
+        locks: &State<SessionLocks>,
+        claim: Claims,
+        id: String,
+        rotation_party_two_first_message: Json<party_two::PDLFirstMessage>,
+    ) -> Result<Json<party_one::PDLFirstMessage>, EngineError> {
+        let db = state.inner();
+        let key = DbIndex {
+            customerId: claim.sub.to_string(),
+            id: id.clone(),
+        };
+        let _session = locks.lock(&key).await;
+        let rotation = claim_stage(db.as_ref(), &key, RotationStage::SecondDone, "third").await?;
+
+        let party_one_private_new = db
+            .get(&key, &EcdsaStruct::RotatePrivateNew)
+            .await?
+            .ok_or(EngineError::MissingState(id.to_string()))?;
+
+        let party_one_private_new = take::<Party1Private>(party_one_private_new, &id)?;
+        let rotation_party_two_first_message = rotation_party_two_first_message.into_inner();
+        let first_message = rotation_party_two_first_message.clone();
+        let (rotation_party_one_second_message, party_one_pdl_decommit, alpha) =
+            timed_blocking("pdl_prove", move || {
+                MasterKey1::rotation_second_message(&first_message, &party_one_private_new)
+            })
+            .await?;
+
+        db.insert(&key, &EcdsaStruct::RotatePDLDecommit, &party_one_pdl_decommit)
+            .await?;
+        db.insert(&key, &EcdsaStruct::RotateAlpha, &Alpha { value: alpha })
+            .await?;
+        db.insert(
+            &key,
+            &EcdsaStruct::RotateParty2PDLFirstMsg,
+            &rotation_party_two_first_message,
+        )
+            .await?;
+        let rotation_party_one_second_message = RotatePDLFirstMsg {
+            value: rotation_party_one_second_message,
+        };
+        db.insert(
+            &key,
+            &EcdsaStruct::RotateParty1PDLFirstMsg,
+            &rotation_party_one_second_message,
+        )
+            .await?;
+        complete_stage(db.as_ref(), &key, &rotation, RotationStage::ThirdDone).await?;
+
+        Ok(Json(rotation_party_one_second_message.value))
+    }
+
+    ///last round of rotation: verifies party two's PDL proof and replaces the master key with the rotated one
+    async fn rotate_fourth(
+        state: &State<Arc<dyn Db>>,
+        locks: &State<SessionLocks>,
+        claim: Claims,
+        id: String,
+        rotation_party_two_second_message: Json<party_two::PDLSecondMessage>,
+    ) -> Result<Json<party_one::PDLSecondMessage>, EngineError> {
+        let db = state.inner();
+        let key = DbIndex {
+            customerId: claim.sub.to_string(),
+            id: id.clone(),
+        };
+        let _session = locks.lock(&key).await;
+        claim_stage(db.as_ref(), &key, RotationStage::ThirdDone, "fourth").await?;
+        //the key may have been tainted by a signature since the first round
+        check_not_tainted(db.as_ref(), &key).await?;
+
+        let master_key = db
+            .get(&key, &EcdsaStruct::Party1MasterKey)
+            .await?
+            .ok_or(EngineError::MissingState(id.to_string()))?;
+        let rotation_party_one_first_message = db
+            .get(&key, &EcdsaStruct::RotateFirstMsg)
+            .await?
+            .ok_or(EngineError::MissingState(id.to_string()))?;
+        let party_one_private_new = db
+            .get(&key, &EcdsaStruct::RotatePrivateNew)
+            .await?
+            .ok_or(EngineError::MissingState(id.to_string()))?;
+        let random1 = db
+            .get(&key, &EcdsaStruct::RotateRandom)
+            .await?
+            .ok_or(EngineError::MissingState(id.to_string()))?;
+        let rotation_party_one_second_message = db
+            .get(&key, &EcdsaStruct::RotateParty1PDLFirstMsg)
+            .await?
+            .ok_or(EngineError::MissingState(id.to_string()))?;
+        let rotation_party_two_first_message = db
+            .get(&key, &EcdsaStruct::RotateParty2PDLFirstMsg)
+            .await?
+            .ok_or(EngineError::MissingState(id.to_string()))?;
+        let party_one_pdl_decommit = db
+            .get(&key, &EcdsaStruct::RotatePDLDecommit)
+            .await?
+            .ok_or(EngineError::MissingState(id.to_string()))?;
+        let alpha = db
+            .get(&key, &EcdsaStruct::RotateAlpha)
+            .await?
+            .ok_or(EngineError::MissingState(id.to_string()))?;
+
+        let master_key = take::<MasterKey1>(master_key, &id)?;
+        let public_key = master_key.public.q.clone();
+        let rotation_party_one_first_message =
+            take::<RotateFirstMsg>(rotation_party_one_first_message, &id)?.value;
+        let party_one_private_new = take::<Party1Private>(party_one_private_new, &id)?;
+        let random1 = take::<RotateRandom>(random1, &id)?.value;
+        let rotation_party_one_second_message =
+            take::<RotatePDLFirstMsg>(rotation_party_one_second_message, &id)?.value;
+        let rotation_party_two_first_message =
+            take::<party_two::PDLFirstMessage>(rotation_party_two_first_message, &id)?;
+        let party_one_pdl_decommit = take::<PDLdecommit>(party_one_pdl_decommit, &id)?;
+        let alpha = std::mem::replace(&mut take::<Alpha>(alpha, &id)?.value, BigInt::from(0));
+        let rotation_party_two_second_message = rotation_party_two_second_message.into_inner();
+        //the master key comes back to be compared with the stored one
+        let (res, master_key) = timed_blocking("pdl_verify", move || {
+            let res = master_key.rotation_third_message(
+                &rotation_party_one_first_message,
+                party_one_private_new,
+                &random1,
+                &rotation_party_one_second_message,
+                &rotation_party_two_first_message,
+                &rotation_party_two_second_message,
+                party_one_pdl_decommit,
+                alpha,
+            );
+            (res, master_key)
+        })
+        .await?;
+
+        //whatever the outcome, the rotation has to restart from the first round
+        if let Err(e) = cleanup::delete_rotation(db.as_ref(), &key).await {
+            warn!("Failed to delete the rotation state, id: {}: {}", id, e);
+        }
+
+        let (rotation_party_one_third_message, master_key_rotated) = res.or(Err(
+            EngineError::VerificationFailed(format!("PDL proof of party two failed, id: {}", id)),
+        ))?;
+        if master_key_rotated.public.q != public_key {
+            return Err(EngineError::Internal(format!(
+                "Rotation changed the public key, id: {}",
+                id
+            )));
+        }
+
+        //a single write, the previous share stays in place until the rotated one is complete, and only replaces the
+        //share this rotation started from
+        if !db
+            .compare_and_swap(
+                &key,
+                &EcdsaStruct::Party1MasterKey,
+                Some(&master_key),
+                &master_key_rotated,
+            )
+            .await?
+        {
+            return Err(EngineError::OutOfOrder(format!(
+                "the master key of {} changed during the rotation",
+                id
+            )));
+        }
+
+        Ok(Json(rotation_party_one_third_message))
+    }
+}
+
+/// Claims the first round of a rotation, which restarts any rotation of the key unless one of its rounds is running.
+/// Like the rounds of keygen the claim is a [Db::compare_and_swap], so that it holds across engine instances
+async fn claim_start(db: &dyn Db, key: &DbIndex) -> Result<RotationState, EngineError> {
+    let current = db.get(key, &EcdsaStruct::RotationState).await?;
+    if let Some(current) = &current {
+        let state = downcast::<RotationState>(current.as_ref(), &key.id)?;
+        if state.in_progress {
+            return Err(EngineError::OutOfOrder(format!(
+                "round first of rotation {} found {:?} with a round in progress",
+                key.id, state.stage
+            )));
+        }
+    }
+
+    let claimed = RotationState {
+        stage: RotationStage::Started,
+        started_at: Utc::now().timestamp(),
+        in_progress: true,
+    };
+    if !db
+        .compare_and_swap(key, &EcdsaStruct::RotationState, current.as_deref(), &claimed)
+        .await?
+    {
+        return Err(EngineError::OutOfOrder(format!(
+            "round first of rotation {} raced with another round",
+            key.id
+        )));
+    }
+    Ok(claimed)
+}
+
+/// Claims a round of the rotation, failing unless `expected` is its last completed round and no other round of it is
+/// running. Returns the claimed state for [complete_stage]
+async fn claim_stage(
+    db: &dyn Db,
+    key: &DbIndex,
+    expected: RotationStage,
+    round: &str,
+) -> Result<RotationState, EngineError> {
+    let current = db
+        .get(key, &EcdsaStruct::RotationState)
+        .await?
+        .ok_or(EngineError::OutOfOrder(format!(
+            "round {} of rotation {} expects {:?}, no rotation is ongoing",
+            round, key.id, expected
+        )))?;
+    let state = downcast::<RotationState>(current.as_ref(), &key.id)?.clone();
+    if state.stage != expected || state.in_progress {
+        return Err(EngineError::OutOfOrder(format!(
+            "round {} of rotation {} expects {:?}, found {:?}{}",
+            round,
+            key.id,
+            expected,
+            state.stage,
+            if state.in_progress { " with a round in progress" } else { "" }
+        )));
+    }
+
+    let claimed = RotationState {
+        in_progress: true,
+        ..state
+    };
+    if !db
+        .compare_and_swap(key, &EcdsaStruct::RotationState, Some(current.as_ref()), &claimed)
+        .await?
+    {
+        return Err(EngineError::OutOfOrder(format!(
+            "round {} of rotation {} raced with another round",
+            round, key.id
+        )));
+    }
+    Ok(claimed)
+}
+
+/// Completes the round claimed by [claim_start] or [claim_stage], moving the rotation to `stage`. A round that fails
+/// after its claim leaves the rotation claimed, so that no round of it runs until [cleanup::sweep] expires it
+async fn complete_stage(
+    db: &dyn Db,
+    key: &DbIndex,
+    claimed: &RotationState,
+    stage: RotationStage,
+) -> Result<(), EngineError> {
+    let next = RotationState {
+        stage,
+        in_progress: false,
+        ..claimed.clone()
+    };
+    if !db
+        .compare_and_swap(key, &EcdsaStruct::RotationState, Some(claimed), &next)
+        .await?
+    {
+        return Err(EngineError::OutOfOrder(format!(
+            "rotation {} changed while completing {:?}",
+            key.id, stage
+        )));
+    }
+    Ok(())
+}
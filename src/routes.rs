@@ -2,41 +2,56 @@
 //! to the fact that rockets http server does not allow to mount directly routes as trait functions.
 
 use crate::guarder::Claims;
+use crate::hd::HD;
 use crate::keygen::KeyGen;
+use crate::rotate::Rotate;
+use crate::settings::EngineConfig;
 use crate::sign::Sign;
-use crate::traits::{Db};
-use crate::types::SignSecondMsgRequest;
+use crate::telemetry::round_span;
+use crate::traits::{Db, IdGenerator, IncidentSink, SessionStore, TxAuthorization};
+use crate::types::{ChildKey, EngineError, MasterPublicKey, SessionLocks, SignSecondMsgRequest};
 
 use two_party_ecdsa::{party_one, party_two};
 use two_party_ecdsa::party_one::{KeyGenFirstMsg, DLogProof};
 use two_party_ecdsa::kms::ecdsa::two_party::{party1};
 use two_party_ecdsa::curv::cryptographic_primitives::twoparty::dh_key_exchange_variant_with_pok_comm::{Party1FirstMessage, Party1SecondMessage};
+use two_party_ecdsa::curv::cryptographic_primitives::twoparty::coin_flip_optimal_rounds;
 
 use rocket::serde::json::Json;
 use rocket::{post, get, http::Status, State};
-use tokio::sync::Mutex;
+use std::sync::Arc;
+use tracing::Instrument;
 
 
 #[post("/ecdsa/keygen/first", format = "json")]
 pub async fn wrap_keygen_first(
-    state: &State<Mutex<Box<dyn Db>>>,
+    state: &State<Arc<dyn Db>>,
+    ids: &State<Arc<dyn IdGenerator>>,
+    config: &State<EngineConfig>,
     claim: Claims,
-) -> Result<Json<(String, KeyGenFirstMsg)>, String> {
+) -> Result<Json<(String, KeyGenFirstMsg)>, EngineError> {
     struct Gotham {}
     impl KeyGen for Gotham {}
-    Gotham::first(state, claim).await
+    let span = round_span("keygen_first", &claim.sub, None);
+    Gotham::first(state, ids, config, claim)
+        .instrument(span)
+        .await
 }
 
 #[post("/ecdsa/keygen/<id>/second", format = "json", data = "<dlog_proof>")]
 pub async fn wrap_keygen_second(
-    state: &State<Mutex<Box<dyn Db>>>,
+    state: &State<Arc<dyn Db>>,
+    locks: &State<SessionLocks>,
     claim: Claims,
     id: String,
     dlog_proof: Json<DLogProof>,
-) -> Result<Json<party1::KeyGenParty1Message2>, String> {
+) -> Result<Json<party1::KeyGenParty1Message2>, EngineError> {
     struct Gotham {}
     impl KeyGen for Gotham {}
-    Gotham::second(state, claim, id, dlog_proof).await
+    let span = round_span("keygen_second", &claim.sub, Some(id.as_str()));
+    Gotham::second(state, locks, claim, id, dlog_proof)
+        .instrument(span)
+        .await
 }
 
 #[post(
@@ -45,14 +60,18 @@ format = "json",
 data = "<party_2_pdl_first_message>"
 )]
 pub async fn wrap_keygen_third(
-    state: &State<Mutex<Box<dyn Db>>>,
+    state: &State<Arc<dyn Db>>,
+    locks: &State<SessionLocks>,
     claim: Claims,
     id: String,
     party_2_pdl_first_message: Json<party_two::PDLFirstMessage>,
-) -> Result<Json<party_one::PDLFirstMessage>, String> {
+) -> Result<Json<party_one::PDLFirstMessage>, EngineError> {
     struct Gotham {}
     impl KeyGen for Gotham {}
-    Gotham::third(state, claim, id, party_2_pdl_first_message).await
+    let span = round_span("keygen_third", &claim.sub, Some(id.as_str()));
+    Gotham::third(state, locks, claim, id, party_2_pdl_first_message)
+        .instrument(span)
+        .await
 }
 
 #[post(
@@ -61,25 +80,33 @@ format = "json",
 data = "<party_two_pdl_second_message>"
 )]
 pub async fn wrap_keygen_fourth(
-    state: &State<Mutex<Box<dyn Db>>>,
+    state: &State<Arc<dyn Db>>,
+    locks: &State<SessionLocks>,
     claim: Claims,
     id: String,
     party_two_pdl_second_message: Json<party_two::PDLSecondMessage>,
-) -> Result<Json<party_one::PDLSecondMessage>, String> {
+) -> Result<Json<party_one::PDLSecondMessage>, EngineError> {
     struct Gotham {}
     impl KeyGen for Gotham {}
-    Gotham::fourth(state, claim, id, party_two_pdl_second_message).await
+    let span = round_span("keygen_fourth", &claim.sub, Some(id.as_str()));
+    Gotham::fourth(state, locks, claim, id, party_two_pdl_second_message)
+        .instrument(span)
+        .await
 }
 
 #[post("/ecdsa/keygen/<id>/chaincode/first", format = "json")]
 pub async fn wrap_chain_code_first_message(
-    state: &State<Mutex<Box<dyn Db>>>,
+    state: &State<Arc<dyn Db>>,
+    locks: &State<SessionLocks>,
     claim: Claims,
     id: String,
-) -> Result<Json<Party1FirstMessage>, String> {
+) -> Result<Json<Party1FirstMessage>, EngineError> {
     struct Gotham {}
     impl KeyGen for Gotham {}
-    Gotham::chain_code_first_message(state, claim, id).await
+    let span = round_span("chain_code_first_message", &claim.sub, Some(id.as_str()));
+    Gotham::chain_code_first_message(state, locks, claim, id)
+        .instrument(span)
+        .await
 }
 
 #[post(
@@ -88,14 +115,17 @@ format = "json",
 data = "<cc_party_two_first_message_d_log_proof>"
 )]
 pub async fn wrap_chain_code_second_message(
-    state: &State<Mutex<Box<dyn Db>>>,
+    state: &State<Arc<dyn Db>>,
+    locks: &State<SessionLocks>,
     claim: Claims,
     id: String,
     cc_party_two_first_message_d_log_proof: Json<DLogProof>,
-) -> Result<Json<Party1SecondMessage>, String> {
+) -> Result<Json<Party1SecondMessage>, EngineError> {
     struct Gotham {}
     impl KeyGen for Gotham {}
-    Gotham::chain_code_second_message(state, claim, id, cc_party_two_first_message_d_log_proof)
+    let span = round_span("chain_code_second_message", &claim.sub, Some(id.as_str()));
+    Gotham::chain_code_second_message(state, locks, claim, id, cc_party_two_first_message_d_log_proof)
+        .instrument(span)
         .await
 }
 
@@ -105,26 +135,40 @@ format = "json",
 data = "<eph_key_gen_first_message_party_two>"
 )]
 pub async fn wrap_sign_first(
-    state: &State<Mutex<Box<dyn Db>>>,
+    state: &State<Arc<dyn Db>>,
+    sessions: &State<Arc<dyn SessionStore>>,
+    locks: &State<SessionLocks>,
+    config: &State<EngineConfig>,
     claim: Claims,
     id: String,
     eph_key_gen_first_message_party_two: Json<party_two::EphKeyGenFirstMsg>,
-) -> Result<Json<party_one::EphKeyGenFirstMsg>, String> {
+) -> Result<Json<party_one::EphKeyGenFirstMsg>, EngineError> {
     struct Gotham {}
     impl Sign for Gotham {}
-    Gotham::sign_first(state, claim, id, eph_key_gen_first_message_party_two).await
+    let span = round_span("sign_first", &claim.sub, Some(id.as_str()));
+    Gotham::sign_first(state, sessions, locks, config, claim, id, eph_key_gen_first_message_party_two)
+        .instrument(span)
+        .await
 }
 
 #[post("/ecdsa/sign/<id>/second", format = "json", data = "<request>")]
 pub async fn wrap_sign_second(
-    state: &State<Mutex<Box<dyn Db>>>,
+    state: &State<Arc<dyn Db>>,
+    sessions: &State<Arc<dyn SessionStore>>,
+    locks: &State<SessionLocks>,
+    config: &State<EngineConfig>,
+    authorizer: &State<Arc<dyn TxAuthorization>>,
+    incidents: &State<Arc<dyn IncidentSink>>,
     claim: Claims,
     id: String,
     request: Json<SignSecondMsgRequest>,
-) -> Result<Json<party_one::SignatureRecid>, String> {
+) -> Result<Json<party_one::SignatureRecid>, EngineError> {
     struct Gotham {}
     impl Sign for Gotham {}
-    Gotham::sign_second(state, claim, id, request).await
+    let span = round_span("sign_second", &claim.sub, Some(id.as_str()));
+    Gotham::sign_second(state, sessions, locks, config, authorizer, incidents, claim, id, request)
+        .instrument(span)
+        .await
 }
 
 #[post(
@@ -133,28 +177,167 @@ format = "json",
 data = "<eph_key_gen_first_message_party_two>"
 )]
 pub async fn wrap_sign_first_v2(
-    state: &State<Mutex<Box<dyn Db>>>,
+    state: &State<Arc<dyn Db>>,
+    sessions: &State<Arc<dyn SessionStore>>,
+    ids: &State<Arc<dyn IdGenerator>>,
+    config: &State<EngineConfig>,
     claim: Claims,
     id: String,
     eph_key_gen_first_message_party_two: Json<party_two::EphKeyGenFirstMsg>,
-) -> Result<Json<(String, party_one::EphKeyGenFirstMsg)>, String> {
+) -> Result<Json<(String, party_one::EphKeyGenFirstMsg)>, EngineError> {
     struct Gotham {}
     impl Sign for Gotham {}
-    Gotham::sign_first_v2(state, claim, id, eph_key_gen_first_message_party_two).await
+    let span = round_span("sign_first_v2", &claim.sub, Some(id.as_str()));
+    Gotham::sign_first_v2(state, sessions, ids, config, claim, id, eph_key_gen_first_message_party_two)
+        .instrument(span)
+        .await
 }
 
 #[post("/ecdsa/sign/<ssid>/second_v2", format = "json", data = "<request>")]
 pub async fn wrap_sign_second_v2(
-    state: &State<Mutex<Box<dyn Db>>>,
+    state: &State<Arc<dyn Db>>,
+    sessions: &State<Arc<dyn SessionStore>>,
+    locks: &State<SessionLocks>,
+    config: &State<EngineConfig>,
+    authorizer: &State<Arc<dyn TxAuthorization>>,
+    incidents: &State<Arc<dyn IncidentSink>>,
     claim: Claims,
     ssid: String,
     request: Json<SignSecondMsgRequest>,
-) -> Result<Json<party_one::SignatureRecid>, String> {
+) -> Result<Json<party_one::SignatureRecid>, EngineError> {
     struct Gotham {}
     impl Sign for Gotham {}
-    Gotham::sign_second_v2(state, claim, ssid, request).await
+    let span = round_span("sign_second_v2", &claim.sub, ssid.split_once(',').map(|(id, _)| id));
+    span.record("ssid", ssid.as_str());
+    Gotham::sign_second_v2(state, sessions, locks, config, authorizer, incidents, claim, ssid, request)
+        .instrument(span)
+        .await
+}
+
+#[post("/ecdsa/rotate/<id>/first", format = "json")]
+pub async fn wrap_rotate_first(
+    state: &State<Arc<dyn Db>>,
+    locks: &State<SessionLocks>,
+    claim: Claims,
+    id: String,
+) -> Result<Json<coin_flip_optimal_rounds::Party1FirstMessage>, EngineError> {
+    struct Gotham {}
+    impl Rotate for Gotham {}
+    let span = round_span("rotate_first", &claim.sub, Some(id.as_str()));
+    Gotham::rotate_first(state, locks, claim, id)
+        .instrument(span)
+        .await
 }
 
+#[post(
+"/ecdsa/rotate/<id>/second",
+format = "json",
+data = "<party2_first_message>"
+)]
+pub async fn wrap_rotate_second(
+    state: &State<Arc<dyn Db>>,
+    locks: &State<SessionLocks>,
+    claim: Claims,
+    id: String,
+    party2_first_message: Json<coin_flip_optimal_rounds::Party2FirstMessage>,
+) -> Result<
+    Json<(
+        coin_flip_optimal_rounds::Party1SecondMessage,
+        party1::RotationParty1Message1,
+    )>,
+    EngineError,
+> {
+    struct Gotham {}
+    impl Rotate for Gotham {}
+    let span = round_span("rotate_second", &claim.sub, Some(id.as_str()));
+    Gotham::rotate_second(state, locks, claim, id, party2_first_message)
+        .instrument(span)
+        .await
+}
+
+#[post(
+"/ecdsa/rotate/<id>/third",
+format = "json",
+data = "<rotation_party_two_first_message>"
+)]
+pub async fn wrap_rotate_third(
+    state: &State<Arc<dyn Db>>,
+    locks: &State<SessionLocks>,
+    claim: Claims,
+    id: String,
+    rotation_party_two_first_message: Json<party_two::PDLFirstMessage>,
+) -> Result<Json<party_one::PDLFirstMessage>, EngineError> {
+    struct Gotham {}
+    impl Rotate for Gotham {}
+    let span = round_span("rotate_third", &claim.sub, Some(id.as_str()));
+    Gotham::rotate_third(state, locks, claim, id, rotation_party_two_first_message)
+        .instrument(span)
+        .await
+}
+
+#[post(
+"/ecdsa/rotate/<id>/fourth",
+format = "json",
+data = "<rotation_party_two_second_message>"
+)]
+pub async fn wrap_rotate_fourth(
+    state: &State<Arc<dyn Db>>,
+    locks: &State<SessionLocks>,
+    claim: Claims,
+    id: String,
+    rotation_party_two_second_message: Json<party_two::PDLSecondMessage>,
+) -> Result<Json<party_one::PDLSecondMessage>, EngineError> {
+    struct Gotham {}
+    impl Rotate for Gotham {}
+    let span = round_span("rotate_fourth", &claim.sub, Some(id.as_str()));
+    Gotham::rotate_fourth(state, locks, claim, id, rotation_party_two_second_message)
+        .instrument(span)
+        .await
+}
+
+#[post("/ecdsa/<id>/child", format = "json")]
+pub async fn wrap_allocate_child(
+    state: &State<Arc<dyn Db>>,
+    claim: Claims,
+    id: String,
+) -> Result<Json<ChildKey>, EngineError> {
+    struct Gotham {}
+    impl HD for Gotham {}
+    let span = round_span("allocate_child", &claim.sub, Some(id.as_str()));
+    Gotham::allocate_child(state, claim, id)
+        .instrument(span)
+        .await
+}
+
+#[get("/ecdsa/<id>/public")]
+pub async fn wrap_public_key(
+    state: &State<Arc<dyn Db>>,
+    claim: Claims,
+    id: String,
+) -> Result<Json<MasterPublicKey>, EngineError> {
+    struct Gotham {}
+    impl HD for Gotham {}
+    let span = round_span("public_key", &claim.sub, Some(id.as_str()));
+    Gotham::public_key(state, claim, id, None)
+        .instrument(span)
+        .await
+}
+
+#[get("/ecdsa/<id>/public/<x_pos>/<y_pos>")]
+pub async fn wrap_child_public_key(
+    state: &State<Arc<dyn Db>>,
+    claim: Claims,
+    id: String,
+    x_pos: u32,
+    y_pos: u32,
+) -> Result<Json<MasterPublicKey>, EngineError> {
+    struct Gotham {}
+    impl HD for Gotham {}
+    let span = round_span("child_public_key", &claim.sub, Some(id.as_str()));
+    Gotham::public_key(state, claim, id, Some((x_pos, y_pos)))
+        .instrument(span)
+        .await
+}
 
 #[get("/health")]
 pub fn ping() -> Status {
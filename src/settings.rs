@@ -0,0 +1,118 @@
+//! Typed configuration of the engine, loaded from `Settings.toml` with `GOTHAM_` environment variable overrides
+//! (e.g. `GOTHAM_CHECK_ACTIVE_SHARE=true` overrides `check_active_share`) and passed to the routes as rocket managed
+//! state. Only variables with the prefix are read, the rest of the process environment is not merged in.
+use crate::types::{Authenticator, LogFormat};
+
+use config::{Config, ConfigError, Environment, File};
+use serde::Deserialize;
+use std::env;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct EngineConfig {
+    /// Search for active shares of the customer before KeyGen
+    #[serde(default)]
+    pub check_active_share: bool,
+    /// Abort KeyGen when the customer already has an active share. Only applies with `check_active_share`
+    #[serde(default)]
+    pub fail_keygen_if_active_share_exists: bool,
+    /// Url of redis for [crate::redis_store::RedisStore::from_config], e.g. `redis://127.0.0.1:6379`
+    #[serde(default)]
+    pub redis_url: Option<String>,
+    /// The env prefix of the table names, see [crate::traits::MPCStruct::to_table_name]
+    #[serde(default = "default_table_env")]
+    pub table_env: String,
+    /// How the customer id of a request is authenticated
+    #[serde(default = "default_authenticator")]
+    pub authenticator: Authenticator,
+    /// Path or url of the JWKS when `authenticator = "jwt"`
+    #[serde(default)]
+    pub jwks: String,
+    #[serde(default)]
+    pub issuer: String,
+    #[serde(default)]
+    pub audience: String,
+    /// KeyGen sessions that do not set their master key within this many seconds are deleted by
+    /// [crate::cleanup::spawn_sweeper]
+    #[serde(default = "default_keygen_session_ttl_secs")]
+    pub keygen_session_ttl_secs: u64,
+    /// The ephemeral state of a cross-session Sign expires from the [crate::traits::SessionStore] after this many seconds
+    #[serde(default = "default_sign_session_ttl_secs")]
+    pub sign_session_ttl_secs: u64,
+    /// Sign only with child keys allocated through [crate::hd::HD::allocate_child]
+    #[serde(default)]
+    pub enforce_allocated_child_keys: bool,
+    /// The format of the logs set up by [crate::telemetry::init]
+    #[serde(default = "default_log_format")]
+    pub log_format: LogFormat,
+}
+
+fn default_table_env() -> String {
+    "local".to_string()
+}
+
+fn default_authenticator() -> Authenticator {
+    Authenticator::None
+}
+
+fn default_log_format() -> LogFormat {
+    LogFormat::Json
+}
+
+fn default_keygen_session_ttl_secs() -> u64 {
+    3600
+}
+
+fn default_sign_session_ttl_secs() -> u64 {
+    300
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        EngineConfig {
+            check_active_share: false,
+            fail_keygen_if_active_share_exists: false,
+            redis_url: None,
+            table_env: default_table_env(),
+            authenticator: default_authenticator(),
+            jwks: String::new(),
+            issuer: String::new(),
+            audience: String::new(),
+            keygen_session_ttl_secs: default_keygen_session_ttl_secs(),
+            sign_session_ttl_secs: default_sign_session_ttl_secs(),
+            enforce_allocated_child_keys: false,
+            log_format: default_log_format(),
+        }
+    }
+}
+
+impl EngineConfig {
+    /// Loads `Settings.toml` from the working directory, if present, and the environment overrides
+    pub fn load() -> Result<Self, ConfigError> {
+        Self::from_file("Settings")
+    }
+
+    /// Loads the settings file `name` (the extension is optional), if present, and the `GOTHAM_` environment
+    /// overrides. The legacy variables are still honored: `REDIS_ENV` enables the active share check and
+    /// `ELASTICACHE_URL` sets the redis host.
+    pub fn from_file(name: &str) -> Result<Self, ConfigError> {
+        let settings = Config::builder()
+            .add_source(File::with_name(name).required(false))
+            .add_source(
+                Environment::with_prefix("GOTHAM")
+                    .prefix_separator("_")
+                    .separator("__"),
+            )
+            .build()?;
+
+        let mut config: EngineConfig = settings.try_deserialize()?;
+        if env::var_os("REDIS_ENV").is_some() {
+            config.check_active_share = true;
+        }
+        if config.redis_url.is_none() {
+            config.redis_url = env::var("ELASTICACHE_URL")
+                .ok()
+                .map(|host| format!("redis://{}", host));
+        }
+        Ok(config)
+    }
+}
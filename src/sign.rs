@@ -1,369 +1,451 @@
-use std::env;
 use crate::guarder::Claims;
-use crate::traits::{Db, RedisMod};
-use crate::types::{DbIndex, EcdsaStruct, SignSecondMsgRequest, idify, Aborted};
-use config::Value;
+use crate::hd::check_allocated;
+use crate::keygen::downcast;
+use crate::settings::EngineConfig;
+use crate::telemetry::timed;
+use crate::traits::{Db, IdGenerator, IncidentSink, SessionStore, TxAuthorization};
+use crate::types::{DbIndex, EcdsaStruct, EngineError, SessionLocks, SignSecondMsgRequest, SignatureIncident, TxAuthorizationRequest, TxDecision, Aborted};
 
 use two_party_ecdsa::kms::ecdsa::two_party::MasterKey1;
-use two_party_ecdsa::party_one::{Converter, v};
+use two_party_ecdsa::party_one::v;
 use two_party_ecdsa::{party_one, party_two, BigInt};
 
+use chrono::Utc;
 use rocket::serde::json::Json;
 use rocket::{async_trait, State};
-use tokio::sync::Mutex;
-use uuid::Uuid;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, error, Span};
 
 #[async_trait]
 pub trait Sign {
     async fn sign_first(
-        state: &State<Mutex<Box<dyn Db>>>,
+        state: &State<Arc<dyn Db>>,
+        sessions: &State<Arc<dyn SessionStore>>,
+        locks: &State<SessionLocks>,
+        config: &State<EngineConfig>,
         claim: Claims,
         id: String,
         eph_key_gen_first_message_party_two: Json<party_two::EphKeyGenFirstMsg>,
-    ) -> Result<Json<party_one::EphKeyGenFirstMsg>, String> {
-        let db = state.lock().await;
-
-        let abort = db
-            .get(
-                &DbIndex {
-                    customerId: claim.sub.to_string(),
-                    id: id.clone(),
-                },
-                &EcdsaStruct::Abort,
-            )
-            .await
-            .unwrap_or_else(|err|
-                panic!("DatabaseError: {}", err))
-            .unwrap_or(
-                Box::new(v { value: "false".to_string() }));
-
-        let abort_res = abort.as_any().downcast_ref::<v>().unwrap();
-
-        if abort_res.value == "true" {
-            panic!("Tainted user");
-        }
-
-        let (sign_party_one_first_message, eph_ec_key_pair_party1) =
-            MasterKey1::sign_first_message();
-
-        db.insert(
-            &DbIndex {
+    ) -> Result<Json<party_one::EphKeyGenFirstMsg>, EngineError> {
+        let db = state.inner();
+        let _session = locks
+            .lock(&DbIndex {
                 customerId: claim.sub.to_string(),
                 id: id.clone(),
-            },
-            &EcdsaStruct::EphKeyGenFirstMsg,
-            &eph_key_gen_first_message_party_two.0,
-        )
-            .await
-            .or(Err("Failed to insert into db"))?;
+            })
+            .await;
 
-        db.insert(
+        check_not_tainted(
+            db.as_ref(),
             &DbIndex {
                 customerId: claim.sub.to_string(),
                 id: id.clone(),
             },
-            &EcdsaStruct::EphEcKeyPair,
-            &eph_ec_key_pair_party1,
         )
-            .await
-            .or(Err("Failed to insert into db"))?;
-
-        Ok(Json(sign_party_one_first_message))
-    }
-    async fn sign_second(
-        state: &State<Mutex<Box<dyn Db>>>,
-        claim: Claims,
-        id: String,
-        request: Json<SignSecondMsgRequest>,
-    ) -> Result<Json<party_one::SignatureRecid>, String> {
-        let db = state.lock().await;
-        if env::var("REDIS_ENV").is_ok() {
-            if db.granted(&*request.message.to_hex().to_string(), claim.sub.as_str())==Ok(false) {
-                panic!(
-                    "Unauthorized transaction from redis-pps: {:?}",
-                    id.clone().to_string()
-                );
-            }
-        }
+        .await?;
 
-        //: MasterKey1
-        let master_key = db
-            .get(
-                &DbIndex {
-                    customerId: claim.sub.to_string(),
-                    id: id.clone(),
-                },
-                &EcdsaStruct::Party1MasterKey,
-            )
-            .await
-            .or(Err("Failed to get from db"))?
-            .ok_or(format!("No data for such identifier {}", id))?;
-
-        let x: BigInt = request.x_pos_child_key.clone();
-        let y: BigInt = request.y_pos_child_key.clone();
-
-        let child_master_key = master_key
-            .as_any()
-            .downcast_ref::<MasterKey1>()
-            .unwrap()
-            .get_child(vec![x, y]);
-
-        //: party_one::EphEcKeyPair
-        let eph_ec_key_pair_party1 = db
-            .get(
-                &DbIndex {
-                    customerId: claim.sub.to_string(),
-                    id: id.clone(),
-                },
-                &EcdsaStruct::EphEcKeyPair,
-            )
-            .await
-            .or(Err("Failed to get from db"))?
-            .ok_or(format!("No data for such identifier {}", id))?;
+        let (sign_party_one_first_message, eph_ec_key_pair_party1) =
+            MasterKey1::sign_first_message();
 
-        //: party_two::EphKeyGenFirstMsg
-        let eph_key_gen_first_message_party_two = db
-            .get(
+        //the ephemeral keys are consumed by the second round, a new first round replaces them
+        let ttl = Duration::from_secs(config.sign_session_ttl_secs);
+        sessions
+            .put(
                 &DbIndex {
                     customerId: claim.sub.to_string(),
                     id: id.clone(),
                 },
                 &EcdsaStruct::EphKeyGenFirstMsg,
+                &eph_key_gen_first_message_party_two.0,
+                ttl,
             )
-            .await
-            .or(Err("Failed to get from db"))?
-            .ok_or(format!("No data for such identifier {}", id))?;
-
-        let signature_with_recid = child_master_key.sign_second_message(
-            &request.party_two_sign_message,
-            &eph_key_gen_first_message_party_two
-                .as_any()
-                .downcast_ref::<party_two::EphKeyGenFirstMsg>()
-                .unwrap(),
-            &eph_ec_key_pair_party1
-                .as_any()
-                .downcast_ref::<party_one::EphEcKeyPair>()
-                .unwrap(),
-            &request.message,
-        );
+            .await?;
 
-        if signature_with_recid.is_err() {
-            let value = v {
-                value: "true".parse().unwrap(),
-            };
-            println!("signature failed, user tainted[{:?}]", id);
-            db.insert(
+        sessions
+            .put(
                 &DbIndex {
                     customerId: claim.sub.to_string(),
                     id: id.clone(),
                 },
-                &EcdsaStruct::Abort,
-                &value,
+                &EcdsaStruct::EphEcKeyPair,
+                &eph_ec_key_pair_party1,
+                ttl,
             )
-                .await
-                .or(Err("Failed to insert into db"))?;
-            panic!("Server sign_second: validation of signature failed. Potential adversary")
+            .await?;
+
+        Ok(Json(sign_party_one_first_message))
+    }
+    async fn sign_second(
+        state: &State<Arc<dyn Db>>,
+        sessions: &State<Arc<dyn SessionStore>>,
+        locks: &State<SessionLocks>,
+        config: &State<EngineConfig>,
+        authorizer: &State<Arc<dyn TxAuthorization>>,
+        incidents: &State<Arc<dyn IncidentSink>>,
+        claim: Claims,
+        id: String,
+        request: Json<SignSecondMsgRequest>,
+    ) -> Result<Json<party_one::SignatureRecid>, EngineError> {
+        let db = state.inner();
+        let key = DbIndex {
+            customerId: claim.sub.to_string(),
+            id: id.clone(),
         };
+        let _session = locks.lock(&key).await;
+        check_allocated(db.as_ref(), config, &key, &request).await?;
+        let child_path = [request.x_pos_child_key.clone(), request.y_pos_child_key.clone()];
+        let tx = TxAuthorizationRequest {
+            customer_id: &claim.sub,
+            key_id: &id,
+            message: &request.message,
+            child_path: &child_path,
+            metadata: request.tx_metadata.as_ref(),
+        };
+        authorize(authorizer.inner().as_ref(), &tx).await?;
+        let signature: Result<_, EngineError> = async {
+            //: MasterKey1
+            let master_key = db
+                .get(
+                    &DbIndex {
+                        customerId: claim.sub.to_string(),
+                        id: id.clone(),
+                    },
+                    &EcdsaStruct::Party1MasterKey,
+                )
+                .await?
+                .ok_or(EngineError::MissingState(id.to_string()))?;
+
+            let x: BigInt = request.x_pos_child_key.clone();
+            let y: BigInt = request.y_pos_child_key.clone();
+
+            let child_master_key = downcast::<MasterKey1>(master_key.as_ref(), &id)?.get_child(vec![x, y]);
+
+            //: party_one::EphEcKeyPair, taken atomically so that the nonce signs at most one message
+            let eph_ec_key_pair_party1 = sessions
+                .take(
+                    &DbIndex {
+                        customerId: claim.sub.to_string(),
+                        id: id.clone(),
+                    },
+                    &EcdsaStruct::EphEcKeyPair,
+                )
+                .await?
+                .ok_or(EngineError::MissingState(id.to_string()))?;
+            let eph_ec_key_pair_party1 =
+                downcast::<party_one::EphEcKeyPair>(eph_ec_key_pair_party1.as_ref(), &id)?;
+
+            //: party_two::EphKeyGenFirstMsg
+            let eph_key_gen_first_message_party_two = sessions
+                .take(
+                    &DbIndex {
+                        customerId: claim.sub.to_string(),
+                        id: id.clone(),
+                    },
+                    &EcdsaStruct::EphKeyGenFirstMsg,
+                )
+                .await?
+                .ok_or(EngineError::MissingState(id.to_string()))?;
+            let eph_key_gen_first_message_party_two = downcast::<party_two::EphKeyGenFirstMsg>(
+                eph_key_gen_first_message_party_two.as_ref(),
+                &id,
+            )?;
+
+            let signature_with_recid = match timed("sign_second_message", || {
+                child_master_key.sign_second_message(
+                    &request.party_two_sign_message,
+                    eph_key_gen_first_message_party_two,
+                    eph_ec_key_pair_party1,
+                    &request.message,
+                )
+            }) {
+                Ok(signature_with_recid) => signature_with_recid,
+                Err(_) => {
+                    let value = v {
+                        value: "true".to_string(),
+                    };
+                    record_incident(
+                        incidents.inner().as_ref(),
+                        &SignatureIncident {
+                            customer_id: &claim.sub,
+                            key_id: &id,
+                            ssid: &id,
+                            occurred_at: Utc::now().timestamp(),
+                            message: &request.message,
+                            x_pos_child_key: &request.x_pos_child_key,
+                            y_pos_child_key: &request.y_pos_child_key,
+                            public: &child_master_key.public.q,
+                            party_two_sign_message: &request.party_two_sign_message,
+                            eph_key_gen_first_message_party_two,
+                        },
+                    )
+                    .await;
+                    db.insert(
+                        &DbIndex {
+                            customerId: claim.sub.to_string(),
+                            id: id.clone(),
+                        },
+                        &EcdsaStruct::Abort,
+                        &value,
+                    )
+                        .await?;
+                    return Err(EngineError::VerificationFailed(format!(
+                        "Server sign_second: validation of signature failed. Potential adversary, id: {}",
+                        id
+                    )));
+                }
+            };
 
-        Ok(Json(signature_with_recid.unwrap()))
+            Ok(signature_with_recid)
+        }
+        .await;
+        if signature.is_err() {
+            release(authorizer.inner().as_ref(), &tx).await;
+        }
+        signature.map(Json)
     }
     async fn sign_first_v2(
-        state: &State<Mutex<Box<dyn Db>>>,
+        state: &State<Arc<dyn Db>>,
+        sessions: &State<Arc<dyn SessionStore>>,
+        ids: &State<Arc<dyn IdGenerator>>,
+        config: &State<EngineConfig>,
         claim: Claims,
         id: String,
         eph_key_gen_first_message_party_two: Json<party_two::EphKeyGenFirstMsg>,
-    ) -> Result<Json<(String, party_one::EphKeyGenFirstMsg)>, String> {
-        let db = state.lock().await;
-        println!(
-            "[cross-session] Sign first round - id = {:?} - customerID = {:?}",
-            id, &claim.sub
-        );
+    ) -> Result<Json<(String, party_one::EphKeyGenFirstMsg)>, EngineError> {
+        let db = state.inner();
 
-        let abort = db
-            .get(
-                &DbIndex {
-                    customerId: claim.sub.to_string(),
-                    id: id.clone(),
-                },
-                &EcdsaStruct::Abort,
-            )
-            .await
-            .unwrap_or_else(|err|
-                panic!("DatabaseError: {}", err))
-            .unwrap_or(
-            Box::new(v { value: "false".to_string() }));
-
-        let abort_res = abort.as_any().downcast_ref::<v>().unwrap();
-
-        if abort_res.value == "true" {
-            panic!("Tainted user");
-        }
-
-        struct RedisCon {}
-        impl RedisMod for RedisCon {}
+        check_not_tainted(
+            db.as_ref(),
+            &DbIndex {
+                customerId: claim.sub.to_string(),
+                id: id.clone(),
+            },
+        )
+        .await?;
 
         let (sign_party_one_first_message, eph_ec_key_pair_party1) = MasterKey1::sign_first_message();
-        let sid = Uuid::new_v4().to_string();
+        let sid = ids.next_id();
         let ssid = String::from(id + "," + &*sid);
-        println!("Server side - sign first ssid={:?}", ssid);
+        Span::current().record("ssid", ssid.as_str());
+        debug!("Assigned the ssid of the cross-session signature");
 
-        //write to redis db table as customerid_ssid_EphKeyGenFirstMsg:value
-        let mut key: String = idify(&claim.sub, &ssid, &EcdsaStruct::EphKeyGenFirstMsg);
-        let mut res = RedisCon::redis_set(
-            key.clone(),
-            serde_json::to_string(&eph_key_gen_first_message_party_two.0).unwrap(),
-        )
-            .is_ok();
-        let mut err_msg: String = format!(
-            "redis error during set key-value = {:?} - {:?}",
-            key.clone().to_string(),
-            serde_json::to_string(&eph_key_gen_first_message_party_two.0).unwrap(),
-        );
-
-        if !res {
-            println!("{:?}", err_msg);
-            return Err(format!("{}", err_msg));
-        }
+        let ttl = Duration::from_secs(config.sign_session_ttl_secs);
+        sessions
+            .put(
+                &DbIndex {
+                    customerId: claim.sub.to_string(),
+                    id: ssid.clone(),
+                },
+                &EcdsaStruct::EphKeyGenFirstMsg,
+                &eph_key_gen_first_message_party_two.0,
+                ttl,
+            )
+            .await?;
 
-        //write to redis db table as customerid_ssid_EphEcKeyPair:value
-        key = idify(&claim.sub, &ssid, &EcdsaStruct::EphEcKeyPair);
-        res = RedisCon::redis_set(
-            key.clone(),
-            serde_json::to_string(&eph_ec_key_pair_party1).unwrap(),
-        )
-            .is_ok();
-        err_msg = format!(
-            "redis error during set key-value = {:?} - {:?}",
-            key.clone().to_string(),
-            serde_json::to_string(&eph_ec_key_pair_party1).unwrap()
-        );
-        if !res {
-            println!("{:?}", err_msg);
-            return Err(format!("{}", err_msg));
-        }
+        sessions
+            .put(
+                &DbIndex {
+                    customerId: claim.sub.to_string(),
+                    id: ssid.clone(),
+                },
+                &EcdsaStruct::EphEcKeyPair,
+                &eph_ec_key_pair_party1,
+                ttl,
+            )
+            .await?;
 
         Ok(Json((ssid.clone(), sign_party_one_first_message)))
     }
     async fn sign_second_v2(
-        state: &State<Mutex<Box<dyn Db>>>,
+        state: &State<Arc<dyn Db>>,
+        sessions: &State<Arc<dyn SessionStore>>,
+        locks: &State<SessionLocks>,
+        config: &State<EngineConfig>,
+        authorizer: &State<Arc<dyn TxAuthorization>>,
+        incidents: &State<Arc<dyn IncidentSink>>,
         claim: Claims,
         ssid: String,
         request: Json<SignSecondMsgRequest>,
-    ) -> Result<Json<party_one::SignatureRecid>, String> {
-        let db = state.lock().await;
-        if env::var("REDIS_ENV").is_ok() {
-            if db.granted(&*request.message.to_hex().to_string(), claim.sub.as_str())==Ok(false) {
-                panic!(
-                    "Unauthorized transaction from redis-pps: {:?}",
-                    ssid.clone().to_string()
-                );
-            }
+    ) -> Result<Json<party_one::SignatureRecid>, EngineError> {
+        let db = state.inner();
+
+        let (id, _sid) = ssid
+            .split_once(',')
+            .ok_or(EngineError::BadInput(format!("Malformed ssid {}", ssid)))?;
+        //the lock of the key, not of the ssid, so that the signature does not interleave with a rotation
+        let _session = locks
+            .lock(&DbIndex {
+                customerId: claim.sub.to_string(),
+                id: id.to_string(),
+            })
+            .await;
+        check_allocated(
+            db.as_ref(),
+            config,
+            &DbIndex {
+                customerId: claim.sub.to_string(),
+                id: id.to_string(),
+            },
+            &request,
+        )
+        .await?;
+        let child_path = [request.x_pos_child_key.clone(), request.y_pos_child_key.clone()];
+        let tx = TxAuthorizationRequest {
+            customer_id: &claim.sub,
+            key_id: id,
+            message: &request.message,
+            child_path: &child_path,
+            metadata: request.tx_metadata.as_ref(),
+        };
+        authorize(authorizer.inner().as_ref(), &tx).await?;
+        let signature: Result<_, EngineError> = async {
+            //get the master key for that userid
+            let master_key = db
+                .get(
+                    &DbIndex {
+                        customerId: claim.sub.to_string(),
+                        id: id.clone().to_string(),
+                    },
+                    &EcdsaStruct::Party1MasterKey,
+                )
+                .await?
+                .ok_or(EngineError::MissingState(id.to_string()))?;
+
+            let x: BigInt = request.x_pos_child_key.clone();
+            let y: BigInt = request.y_pos_child_key.clone();
+
+            let child_master_key = downcast::<MasterKey1>(master_key.as_ref(), id)?.get_child(vec![x, y]);
+            //taken atomically, a replayed ssid finds nothing
+            let eph_ec_key_pair_party1 = sessions
+                .take(
+                    &DbIndex {
+                        customerId: claim.sub.to_string(),
+                        id: ssid.clone(),
+                    },
+                    &EcdsaStruct::EphEcKeyPair,
+                )
+                .await?
+                .ok_or(EngineError::MissingState(ssid.to_string()))?;
+            let eph_ec_key_pair_party1 =
+                downcast::<party_one::EphEcKeyPair>(eph_ec_key_pair_party1.as_ref(), &ssid)?;
+
+            let eph_key_gen_first_message_party_two = sessions
+                .take(
+                    &DbIndex {
+                        customerId: claim.sub.to_string(),
+                        id: ssid.clone(),
+                    },
+                    &EcdsaStruct::EphKeyGenFirstMsg,
+                )
+                .await?
+                .ok_or(EngineError::MissingState(ssid.to_string()))?;
+            let eph_key_gen_first_message_party_two = downcast::<party_two::EphKeyGenFirstMsg>(
+                eph_key_gen_first_message_party_two.as_ref(),
+                &ssid,
+            )?;
+
+            let signature_with_recid = match timed("sign_second_message", || {
+                child_master_key.sign_second_message(
+                    &request.party_two_sign_message,
+                    eph_key_gen_first_message_party_two,
+                    eph_ec_key_pair_party1,
+                    &request.message,
+                )
+            }) {
+                Ok(signature_with_recid) => signature_with_recid,
+                Err(_) => {
+                    record_incident(
+                        incidents.inner().as_ref(),
+                        &SignatureIncident {
+                            customer_id: &claim.sub,
+                            key_id: id,
+                            ssid: &ssid,
+                            occurred_at: Utc::now().timestamp(),
+                            message: &request.message,
+                            x_pos_child_key: &request.x_pos_child_key,
+                            y_pos_child_key: &request.y_pos_child_key,
+                            public: &child_master_key.public.q,
+                            party_two_sign_message: &request.party_two_sign_message,
+                            eph_key_gen_first_message_party_two,
+                        },
+                    )
+                    .await;
+
+                    let item = Aborted {
+                        isAborted: "true".to_string(),
+                    };
+
+                    db.insert(&DbIndex {
+                        customerId: claim.sub.to_string(),
+                        id: id.clone().to_string(),
+                    }, &EcdsaStruct::Abort, &item)
+                        .await?;
+                    return Err(EngineError::VerificationFailed(format!(
+                        "Server sign_second: verification of signature failed. Potential adversary, ssid: {}",
+                        ssid
+                    )));
+                }
+            };
+
+            Ok(signature_with_recid)
+        }
+        .await;
+        if signature.is_err() {
+            release(authorizer.inner().as_ref(), &tx).await;
         }
+        signature.map(Json)
+    }
+}
 
-        println!(
-            "[cross-session] Sign second round - id = {:?} - customerID = {:?} - msg = {:?}",
-            ssid,
-            &claim.sub,
-            request.message.to_string()
+/// Records the incident of a failed signature. A failing sink does not keep the key from being tainted
+async fn record_incident(incidents: &dyn IncidentSink, incident: &SignatureIncident<'_>) {
+    if let Err(e) = incidents.record(incident).await {
+        error!(
+            "Failed to record the signature incident, ssid: {}: {}",
+            incident.ssid, e
         );
+    }
+}
 
-        struct RedisCon {}
-        impl RedisMod for RedisCon {}
-
-        let id: &str = ssid.split(",").collect::<Vec<_>>()[0];
-        println!("ssid = {:?}", ssid);
-        println!("id = {:?}", id);
-        println!("sid = {:?}", ssid.split(",").collect::<Vec<_>>()[1]);
-
-        //get the master key for that userid
-        let master_key = db
-            .get(
-                &DbIndex {
-                    customerId: claim.sub.to_string(),
-                    id: id.clone().to_string(),
-                },
-                &EcdsaStruct::Party1MasterKey,
-            )
-            .await
-            .or(Err("Failed to get from db"))?
-            .ok_or(format!("No data for such identifier {}", id))?;
-
-        let x: BigInt = request.x_pos_child_key.clone();
-        let y: BigInt = request.y_pos_child_key.clone();
-
-        let child_master_key = master_key
-            .as_any()
-            .downcast_ref::<MasterKey1>()
-            .unwrap()
-            .get_child(vec![x, y]);
-        let key1 = idify(&claim.sub, &ssid, &EcdsaStruct::EphEcKeyPair);
-        let eph_ec_key_pair_party1: party_one::EphEcKeyPair =
-            serde_json::from_slice(&RedisCon::redis_get(key1.clone()).unwrap().as_bytes()).unwrap();
-
-        let key2 = idify(&claim.sub, &ssid, &EcdsaStruct::EphKeyGenFirstMsg);
-        let eph_key_gen_first_message_party_two: party_two::EphKeyGenFirstMsg =
-            serde_json::from_slice(&RedisCon::redis_get(key2.clone()).unwrap().as_bytes()).unwrap();
-
-        let _ = RedisCon::redis_del(key1);
-        let _ = RedisCon::redis_del(key2);
-
-        let signature_with_recid = child_master_key.sign_second_message(
-            &request.party_two_sign_message,
-            &eph_key_gen_first_message_party_two,
-            &eph_ec_key_pair_party1,
-            &request.message,
-        );
+/// Consults the tx authorization policy for the second round of Sign. A granted tx whose signature is not produced
+/// is handed back by [release]
+async fn authorize(
+    authorizer: &dyn TxAuthorization,
+    tx: &TxAuthorizationRequest<'_>,
+) -> Result<(), EngineError> {
+    match authorizer.granted(tx).await? {
+        TxDecision::Granted => Ok(()),
+        TxDecision::Denied(reason) => Err(EngineError::Unauthorized(format!(
+            "{}, id: {}",
+            reason, tx.key_id
+        ))),
+    }
+}
 
-        if signature_with_recid.is_err() {
-            println!("signature failed, user tainted[{:?}]", id);
-
-            let message: BigInt = request.message.clone();
-            println!("date: {}", chrono::offset::Utc::now().to_string());
-            println!("ssid: {}", ssid.to_string());
-            println!("msg: {}", message.to_string());
-            println!(
-                "party_two_sign_message: {:?}",
-                request.party_two_sign_message.second_message
-            );
-            println!(
-                "partial_sig: {:?}",
-                request.party_two_sign_message.partial_sig
-            );
-            println!("eph_ec_key_pair_party1: {:?}", eph_ec_key_pair_party1);
-            println!(
-                "eph_key_gen_first_message_party_two: {:?}",
-                eph_key_gen_first_message_party_two
-            );
-            println!(
-                "x_pos_child_key: {}",
-                request.x_pos_child_key.clone().to_string()
-            );
-            println!(
-                "y_pos_child_key: {}",
-                request.y_pos_child_key.clone().to_string()
-            );
-            println!("public: {:?}", master_key
-                .as_any()
-                .downcast_ref::<MasterKey1>()
-                .unwrap().public);
-            println!("private {:?}", master_key
-                .as_any()
-                .downcast_ref::<MasterKey1>()
-                .unwrap().private);
-
-            let item = Aborted {
-                isAborted: "true".to_string(),
-            };
+/// Releases a granted tx whose signature was not produced. A failing release does not hide the error of the signature
+async fn release(authorizer: &dyn TxAuthorization, tx: &TxAuthorizationRequest<'_>) {
+    if let Err(e) = authorizer.released(tx).await {
+        error!("Failed to release the tx authorization, id: {}: {}", tx.key_id, e);
+    }
+}
 
-            db.insert(&DbIndex {
-                customerId: claim.sub.to_string(),
-                id: id.clone().to_string(),
-            }, &EcdsaStruct::Abort, &item)
-                .await
-                .or(Err("Failed to insert into db"))?;
-            panic!("Server sign_second: verification of signature failed. Potential adversary")
-        };
+/// Fails if the key is tainted, in which case it can neither sign nor be rotated
+pub(crate) async fn check_not_tainted(db: &dyn Db, key: &DbIndex) -> Result<(), EngineError> {
+    let abort = db.get(key, &EcdsaStruct::Abort).await?;
+    if abort.map_or(false, |abort| is_aborted(abort.as_ref())) {
+        return Err(EngineError::Tainted(key.id.to_string()));
+    }
+    Ok(())
+}
 
-        Ok(Json(signature_with_recid.unwrap()))
+/// A user is tainted once a signature verification failed. Sign v1 marks it with [v] and v2 with [Aborted]
+fn is_aborted(abort: &dyn two_party_ecdsa::party_one::Value) -> bool {
+    if let Some(abort) = abort.as_any().downcast_ref::<v>() {
+        return abort.value == "true";
+    }
+    if let Some(abort) = abort.as_any().downcast_ref::<Aborted>() {
+        return abort.isAborted == "true";
     }
+    false
 }
@@ -0,0 +1,57 @@
+//! Structured logs and tracing spans. Every round runs in a span of [round_span], whose only fields are the round
+//! name and the customer id, key id and ssid, so that secret material can not end up in a span. Expensive steps of
+//! the protocol are timed by [timed], or by [timed_blocking] when they would hold an async worker for long.
+use crate::settings::EngineConfig;
+use crate::types::{EngineError, LogFormat};
+
+use std::time::Instant;
+use tracing::{debug, field, info_span, Span};
+use tracing_subscriber::EnvFilter;
+
+/// Installs the global subscriber in the [EngineConfig::log_format], filtered by `RUST_LOG` (default `info`). Records
+/// of the `log` crate, e.g. rocket's, go through it as well. Fails if a subscriber is already installed
+pub fn init(config: &EngineConfig) -> Result<(), String> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    let res = match config.log_format {
+        LogFormat::Json => builder
+            .json()
+            .with_current_span(true)
+            .with_span_list(false)
+            .try_init(),
+        LogFormat::Text => builder.try_init(),
+    };
+    res.map_err(|e| e.to_string())
+}
+
+/// The span of a round of KeyGen, Sign or Rotate. The key id of a new KeyGen and the ssid of a cross-session Sign
+/// are recorded once known
+pub fn round_span(round: &'static str, customer_id: &str, key_id: Option<&str>) -> Span {
+    info_span!(
+        "round",
+        round,
+        customer_id,
+        key_id,
+        ssid = field::Empty
+    )
+}
+
+/// Runs the step `name` of the protocol and logs how long it took
+pub(crate) fn timed<T>(name: &'static str, step: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let res = step();
+    debug!(step = name, elapsed_ms = start.elapsed().as_millis() as u64, "step done");
+    res
+}
+
+/// Same as [timed] on the blocking thread pool, for the Paillier key generation and the PDL proofs that take up to
+/// seconds. The async workers keep serving the other rounds in the meantime
+pub(crate) async fn timed_blocking<T: Send + 'static>(
+    name: &'static str,
+    step: impl FnOnce() -> T + Send + 'static,
+) -> Result<T, EngineError> {
+    let span = Span::current();
+    tokio::task::spawn_blocking(move || span.in_scope(|| timed(name, step)))
+        .await
+        .map_err(|e| EngineError::Internal(format!("Step {} did not complete: {}", name, e)))
+}
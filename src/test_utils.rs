@@ -0,0 +1,511 @@
+//! Test support: drives the engine routes through Rocket's local [Client] while playing party two with
+//! [MasterKey2]. Implementers can run [certify] against their own [Db] to check it supports the whole
+//! Lindell17 keygen and sign flow.
+use crate::memory_store::InMemoryStore;
+use crate::routes;
+use crate::settings::EngineConfig;
+use crate::traits::{
+    AllowAll, Db, IdGenerator, IncidentSink, LogIncidents, RandomIds, SessionStore, TxAuthorization,
+};
+use crate::types::{SessionLocks, SignSecondMsgRequest};
+
+use two_party_ecdsa::curv::cryptographic_primitives::twoparty::dh_key_exchange_variant_with_pok_comm::{Party1FirstMessage, Party1SecondMessage};
+use two_party_ecdsa::curv::cryptographic_primitives::twoparty::coin_flip_optimal_rounds;
+use two_party_ecdsa::kms::chain_code::two_party::party2::ChainCode2;
+use two_party_ecdsa::kms::rotation::two_party::party2::Rotation2;
+use two_party_ecdsa::kms::ecdsa::two_party::{party1, MasterKey2};
+use two_party_ecdsa::{party_one, BigInt};
+
+use rocket::http::{Header, Status};
+use rocket::local::asynchronous::{Client, LocalResponse};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::sync::Arc;
+
+/// A local client to a rocket instance mounting all the keygen and sign routes on top of `db`. The ephemeral keys of
+/// sign run on an [InMemoryStore]
+pub async fn client(db: Arc<dyn Db>) -> Client {
+    build_client(
+        db,
+        sessions(),
+        Arc::new(RandomIds),
+        Arc::new(LogIncidents),
+        allow_all(),
+        EngineConfig::default(),
+    )
+    .await
+}
+
+/// Same as [client] with the ephemeral keys of sign kept in `sessions`, e.g. a [crate::encrypted_store::EncryptedDb]
+pub async fn client_with_sessions(db: Arc<dyn Db>, sessions: Arc<dyn SessionStore>) -> Client {
+    build_client(
+        db,
+        sessions,
+        Arc::new(RandomIds),
+        Arc::new(LogIncidents),
+        allow_all(),
+        EngineConfig::default(),
+    )
+    .await
+}
+
+/// Same as [client] with the ids of keys and signatures assigned by `ids`, e.g. a [crate::traits::SeededIds]
+pub async fn client_with_ids(db: Arc<dyn Db>, ids: Arc<dyn IdGenerator>) -> Client {
+    build_client(
+        db,
+        sessions(),
+        ids,
+        Arc::new(LogIncidents),
+        allow_all(),
+        EngineConfig::default(),
+    )
+    .await
+}
+
+/// Same as [client_with_sessions] with the incidents of failed signatures recorded by `incidents`
+pub async fn client_with_incidents(
+    db: Arc<dyn Db>,
+    sessions: Arc<dyn SessionStore>,
+    incidents: Arc<dyn IncidentSink>,
+) -> Client {
+    build_client(
+        db,
+        sessions,
+        Arc::new(RandomIds),
+        incidents,
+        allow_all(),
+        EngineConfig::default(),
+    )
+    .await
+}
+
+/// Same as [client] with the txs authorized by `authorizer`, e.g. a [crate::policy::PolicyEngine]
+pub async fn client_with_authorizer(
+    db: Arc<dyn Db>,
+    authorizer: Arc<dyn TxAuthorization>,
+) -> Client {
+    build_client(
+        db,
+        sessions(),
+        Arc::new(RandomIds),
+        Arc::new(LogIncidents),
+        authorizer,
+        EngineConfig::default(),
+    )
+    .await
+}
+
+/// Same as [client] with the engine configured by `config`, e.g. with
+/// [EngineConfig::enforce_allocated_child_keys]
+pub async fn client_with_config(db: Arc<dyn Db>, config: EngineConfig) -> Client {
+    build_client(
+        db,
+        sessions(),
+        Arc::new(RandomIds),
+        Arc::new(LogIncidents),
+        allow_all(),
+        config,
+    )
+    .await
+}
+
+fn sessions() -> Arc<dyn SessionStore> {
+    Arc::new(InMemoryStore::default())
+}
+
+fn allow_all() -> Arc<dyn TxAuthorization> {
+    Arc::new(AllowAll)
+}
+
+async fn build_client(
+    db: Arc<dyn Db>,
+    sessions: Arc<dyn SessionStore>,
+    ids: Arc<dyn IdGenerator>,
+    incidents: Arc<dyn IncidentSink>,
+    authorizer: Arc<dyn TxAuthorization>,
+    config: EngineConfig,
+) -> Client {
+    let rocket = rocket::build()
+        .manage(db)
+        .manage(ids)
+        .manage(incidents)
+        .manage(sessions)
+        .manage(SessionLocks::default())
+        .manage(config)
+        .manage(authorizer)
+        .mount(
+            "/",
+            rocket::routes![
+                routes::wrap_keygen_first,
+                routes::wrap_keygen_second,
+                routes::wrap_keygen_third,
+                routes::wrap_keygen_fourth,
+                routes::wrap_chain_code_first_message,
+                routes::wrap_chain_code_second_message,
+                routes::wrap_sign_first,
+                routes::wrap_sign_second,
+                routes::wrap_sign_first_v2,
+                routes::wrap_sign_second_v2,
+                routes::wrap_rotate_first,
+                routes::wrap_rotate_second,
+                routes::wrap_rotate_third,
+                routes::wrap_rotate_fourth,
+                routes::wrap_allocate_child,
+                routes::wrap_public_key,
+                routes::wrap_child_public_key,
+            ],
+        );
+    Client::tracked(rocket)
+        .await
+        .expect("valid rocket instance")
+}
+
+/// POSTs `body` to `uri` as `customer_id` and returns the response whatever its status, e.g. to check rejections
+pub async fn post_as<'c, B: Serialize>(
+    client: &'c Client,
+    customer_id: &str,
+    uri: String,
+    body: &B,
+) -> LocalResponse<'c> {
+    client
+        .post(uri)
+        .header(Header::new("x-customer-id", customer_id.to_string()))
+        .json(body)
+        .dispatch()
+        .await
+}
+
+/// GETs `uri` as `customer_id` and returns the response whatever its status
+pub async fn get_as<'c>(client: &'c Client, customer_id: &str, uri: String) -> LocalResponse<'c> {
+    client
+        .get(uri)
+        .header(Header::new("x-customer-id", customer_id.to_string()))
+        .dispatch()
+        .await
+}
+
+/// POSTs `body` to `uri` as `customer_id`, failing unless it succeeds, and returns the json response
+pub async fn post<B: Serialize, T: DeserializeOwned>(
+    client: &Client,
+    customer_id: &str,
+    uri: String,
+    body: &B,
+) -> T {
+    let response = post_as(client, customer_id, uri.clone(), body).await;
+    assert_eq!(response.status(), Status::Ok, "POST {} failed", uri);
+    response
+        .into_json()
+        .await
+        .unwrap_or_else(|| panic!("POST {} returned an unexpected body", uri))
+}
+
+/// GETs `uri` as `customer_id`, failing unless it succeeds, and returns the json response
+pub async fn get<T: DeserializeOwned>(client: &Client, customer_id: &str, uri: String) -> T {
+    let response = get_as(client, customer_id, uri.clone()).await;
+    assert_eq!(response.status(), Status::Ok, "GET {} failed", uri);
+    response
+        .into_json()
+        .await
+        .unwrap_or_else(|| panic!("GET {} returned an unexpected body", uri))
+}
+
+/// Runs the first round of keygen only, leaving the session open. Returns the key id assigned by the server
+pub async fn keygen_first(client: &Client, customer_id: &str) -> String {
+    let (id, _): (String, party_one::KeyGenFirstMsg) =
+        post(client, customer_id, "/ecdsa/keygen/first".to_string(), &()).await;
+    id
+}
+
+/// Runs all the keygen rounds as party two. Returns the key id assigned by the server and party two's master key
+pub async fn keygen(client: &Client, customer_id: &str) -> (String, MasterKey2) {
+    let (id, kg_party_one_first_message): (String, party_one::KeyGenFirstMsg) =
+        post(client, customer_id, "/ecdsa/keygen/first".to_string(), &()).await;
+
+    let (kg_party_two_first_message, kg_ec_key_pair_party2) = MasterKey2::key_gen_first_message();
+    let kg_party_one_second_message: party1::KeyGenParty1Message2 = post(
+        client,
+        customer_id,
+        format!("/ecdsa/keygen/{}/second", id),
+        &kg_party_two_first_message.d_log_proof,
+    )
+    .await;
+
+    let (party_two_second_message, party_two_paillier, party_two_pdl_chal) =
+        MasterKey2::key_gen_second_message(
+            &kg_party_one_first_message,
+            &kg_party_one_second_message,
+        )
+        .expect("party one second message is valid");
+
+    let party_one_third_message: party_one::PDLFirstMessage = post(
+        client,
+        customer_id,
+        format!("/ecdsa/keygen/{}/third", id),
+        &party_two_second_message.pdl_first_message,
+    )
+    .await;
+
+    let pdl_decom_party2 = MasterKey2::key_gen_third_message(&party_two_pdl_chal);
+    let party_one_pdl_second_message: party_one::PDLSecondMessage = post(
+        client,
+        customer_id,
+        format!("/ecdsa/keygen/{}/fourth", id),
+        &pdl_decom_party2,
+    )
+    .await;
+
+    MasterKey2::key_gen_fourth_message(
+        &party_two_pdl_chal,
+        &party_one_third_message,
+        &party_one_pdl_second_message,
+    )
+    .expect("party one pdl proof is valid");
+
+    let (cc_party_two_first_message, cc_ec_key_pair2) = ChainCode2::chain_code_first_message();
+    let cc_party_one_first_message: Party1FirstMessage = post(
+        client,
+        customer_id,
+        format!("/ecdsa/keygen/{}/chaincode/first", id),
+        &(),
+    )
+    .await;
+    let cc_party_one_second_message: Party1SecondMessage = post(
+        client,
+        customer_id,
+        format!("/ecdsa/keygen/{}/chaincode/second", id),
+        &cc_party_two_first_message.d_log_proof,
+    )
+    .await;
+    ChainCode2::chain_code_second_message(
+        &cc_party_one_first_message,
+        &cc_party_one_second_message,
+    )
+    .expect("party one chain code message is valid");
+
+    let party2_cc = ChainCode2::compute_chain_code(
+        &cc_ec_key_pair2,
+        &cc_party_one_second_message.comm_witness.public_share,
+    )
+    .chain_code;
+
+    let master_key = MasterKey2::set_master_key(
+        &party2_cc,
+        &kg_ec_key_pair_party2,
+        &kg_party_one_second_message
+            .ecdh_second_message
+            .comm_witness
+            .public_share,
+        &party_two_paillier,
+    );
+
+    (id, master_key)
+}
+
+/// A [client] on a fresh [InMemoryStore] holding one key of `customer_id`, generated by [keygen]. Returns the store,
+/// the client, the key id and party two's master key
+pub async fn keygen_in_memory(customer_id: &str) -> (Arc<dyn Db>, Client, String, MasterKey2) {
+    let db: Arc<dyn Db> = Arc::new(InMemoryStore::default());
+    let client = client(db.clone()).await;
+    let (id, master_key) = keygen(&client, customer_id).await;
+    (db, client, id, master_key)
+}
+
+/// Runs all the rotation rounds as party two. Returns party two's rotated master key, which has the same public key
+pub async fn rotate(
+    client: &Client,
+    customer_id: &str,
+    id: &str,
+    master_key: &MasterKey2,
+) -> MasterKey2 {
+    let coin_flip_party1_first_message: coin_flip_optimal_rounds::Party1FirstMessage = post(
+        client,
+        customer_id,
+        format!("/ecdsa/rotate/{}/first", id),
+        &(),
+    )
+    .await;
+
+    let coin_flip_party2_first_message =
+        Rotation2::key_rotate_first_message(&coin_flip_party1_first_message);
+    let (coin_flip_party1_second_message, rotation_party1_first_message): (
+        coin_flip_optimal_rounds::Party1SecondMessage,
+        party1::RotationParty1Message1,
+    ) = post(
+        client,
+        customer_id,
+        format!("/ecdsa/rotate/{}/second", id),
+        &coin_flip_party2_first_message,
+    )
+    .await;
+
+    let random2 = Rotation2::key_rotate_second_message(
+        &coin_flip_party1_second_message,
+        &coin_flip_party2_first_message,
+        &coin_flip_party1_first_message,
+    );
+    let (rotation_party_two_first_message, party_two_pdl_chal, party_two_paillier) = master_key
+        .rotate_first_message(&random2, &rotation_party1_first_message)
+        .expect("party one rotation message is valid");
+
+    let rotation_party1_second_message: party_one::PDLFirstMessage = post(
+        client,
+        customer_id,
+        format!("/ecdsa/rotate/{}/third", id),
+        &rotation_party_two_first_message,
+    )
+    .await;
+
+    let rotation_party_two_second_message = MasterKey2::rotate_second_message(&party_two_pdl_chal);
+    let rotation_party1_third_message: party_one::PDLSecondMessage = post(
+        client,
+        customer_id,
+        format!("/ecdsa/rotate/{}/fourth", id),
+        &rotation_party_two_second_message,
+    )
+    .await;
+
+    master_key
+        .rotate_third_message(
+            &random2,
+            &party_two_paillier,
+            &party_two_pdl_chal,
+            &rotation_party1_second_message,
+            &rotation_party1_third_message,
+        )
+        .expect("party one pdl proof is valid")
+}
+
+/// Signs `message` with the child key at `(x_pos, y_pos)` of the key `id`, playing party two
+pub async fn sign(
+    client: &Client,
+    customer_id: &str,
+    id: &str,
+    master_key: &MasterKey2,
+    x_pos: BigInt,
+    y_pos: BigInt,
+    message: &BigInt,
+) -> party_one::SignatureRecid {
+    let request = sign_first(client, customer_id, id, master_key, x_pos, y_pos, message).await;
+    post(
+        client,
+        customer_id,
+        format!("/ecdsa/sign/{}/second", id),
+        &request,
+    )
+    .await
+}
+
+/// Runs the first round of [sign] and returns the request of its second round, to be posted to
+/// `/ecdsa/sign/{id}/second`, e.g. with some `tx_metadata`
+pub async fn sign_first(
+    client: &Client,
+    customer_id: &str,
+    id: &str,
+    master_key: &MasterKey2,
+    x_pos: BigInt,
+    y_pos: BigInt,
+    message: &BigInt,
+) -> SignSecondMsgRequest {
+    let child_master_key = master_key.get_child(vec![x_pos.clone(), y_pos.clone()]);
+
+    let (eph_key_gen_first_message_party_two, eph_comm_witness, eph_ec_key_pair_party2) =
+        MasterKey2::sign_first_message();
+    let sign_party_one_first_message: party_one::EphKeyGenFirstMsg = post(
+        client,
+        customer_id,
+        format!("/ecdsa/sign/{}/first", id),
+        &eph_key_gen_first_message_party_two,
+    )
+    .await;
+
+    let party_two_sign_message = child_master_key.sign_second_message(
+        &eph_ec_key_pair_party2,
+        eph_comm_witness,
+        &sign_party_one_first_message,
+        message,
+    );
+
+    SignSecondMsgRequest {
+        message: message.clone(),
+        party_two_sign_message,
+        x_pos_child_key: x_pos,
+        y_pos_child_key: y_pos,
+        tx_metadata: None,
+    }
+}
+
+/// Same as [sign] through the cross-session rounds, where the server assigns an ssid to each signature
+pub async fn sign_v2(
+    client: &Client,
+    customer_id: &str,
+    id: &str,
+    master_key: &MasterKey2,
+    x_pos: BigInt,
+    y_pos: BigInt,
+    message: &BigInt,
+) -> party_one::SignatureRecid {
+    let child_master_key = master_key.get_child(vec![x_pos.clone(), y_pos.clone()]);
+
+    let (eph_key_gen_first_message_party_two, eph_comm_witness, eph_ec_key_pair_party2) =
+        MasterKey2::sign_first_message();
+    let (ssid, sign_party_one_first_message): (String, party_one::EphKeyGenFirstMsg) = post(
+        client,
+        customer_id,
+        format!("/ecdsa/sign/{}/first_v2", id),
+        &eph_key_gen_first_message_party_two,
+    )
+    .await;
+
+    let party_two_sign_message = child_master_key.sign_second_message(
+        &eph_ec_key_pair_party2,
+        eph_comm_witness,
+        &sign_party_one_first_message,
+        message,
+    );
+
+    post(
+        client,
+        customer_id,
+        format!("/ecdsa/sign/{}/second_v2", ssid),
+        &SignSecondMsgRequest {
+            message: message.clone(),
+            party_two_sign_message,
+            x_pos_child_key: x_pos,
+            y_pos_child_key: y_pos,
+            tx_metadata: None,
+        },
+    )
+    .await
+}
+
+/// Runs keygen and a couple of signatures, both single and cross-session, on top of `db` and verifies each signature
+/// against the joint public key
+pub async fn certify(db: Arc<dyn Db>) {
+    let client = client(db).await;
+    let customer_id = "gotham-engine-certification";
+
+    let (id, master_key) = keygen(&client, customer_id).await;
+
+    for (x_pos, y_pos, message) in [(0u32, 0u32, 1234u32), (0, 1, 5678)] {
+        let (x_pos, y_pos, message) =
+            (BigInt::from(x_pos), BigInt::from(y_pos), BigInt::from(message));
+        let child_master_key = master_key.get_child(vec![x_pos.clone(), y_pos.clone()]);
+
+        let signatures = [
+            sign(&client, customer_id, &id, &master_key, x_pos.clone(), y_pos.clone(), &message).await,
+            sign_v2(&client, customer_id, &id, &master_key, x_pos, y_pos, &message).await,
+        ];
+
+        for signature in signatures {
+            party_one::verify(
+                &party_one::Signature {
+                    r: signature.r,
+                    s: signature.s,
+                },
+                &child_master_key.public.q,
+                &message,
+            )
+            .expect("signature is valid for the joint public key");
+        }
+    }
+}
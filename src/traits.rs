@@ -1,13 +1,17 @@
 //! The traits that define the common logic  with default implementation for keygen and sign
 //! while it differentiates implementation of keygen and sign with trait objects for DB management,user authorization and tx authorization
-use std::env;
-
-use crate::types::{DatabaseError, DbIndex};
+use crate::types::{
+    DatabaseError, DbIndex, SignatureIncident, TxAuthorizationRequest, TxDecision,
+};
 
 use two_party_ecdsa::party_one::Value;
 
 use redis::{Commands, Connection, RedisResult};
 use rocket::async_trait;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tracing::{debug, error};
+use uuid::Uuid;
 
 
 /// The Db trait allows different DB's to implement a common API for insert and get
@@ -66,53 +70,174 @@ pub trait Db: Send + Sync {
         key: &DbIndex,
         table_name: &dyn MPCStruct,
     ) -> Result<Option<Box<dyn Value>>, DatabaseError>;
+    ///delete a value from the DB. Deleting a missing value is not an error
+    /// # Arguments
+    /// * `key` - A [DbIndex] struct which acts as a key index in the DB.
+    /// * `table_name` - The table name which is derived from [MPCStruct]
+    async fn delete(&self, key: &DbIndex, table_name: &dyn MPCStruct) -> Result<(), DatabaseError>;
+    ///atomically replace the value if the stored one is still `expected`, `None` meaning that there is no value yet.
+    ///Values are compared by their serialization. Returns whether the value was replaced
+    /// # Examples
+    /// ```
+    /// let swapped = db.compare_and_swap(&key, &EcdsaStruct::POS, Some(&HDPos { pos: 1 }), &HDPos { pos: 2 }).await?;
+    /// ```
+    async fn compare_and_swap(
+        &self,
+        key: &DbIndex,
+        table_name: &dyn MPCStruct,
+        expected: Option<&dyn Value>,
+        value: &dyn Value,
+    ) -> Result<bool, DatabaseError>;
+    ///list the keys of all the values in a table, e.g. for [crate::cleanup::sweep] to find abandoned sessions
+    async fn keys(&self, table_name: &dyn MPCStruct) -> Result<Vec<DbIndex>, DatabaseError>;
     async fn has_active_share(&self, customerId: &str) -> Result<bool, String>;
+}
 
-    /// the granted function implements the logic of tx authorization. If no tx authorization is needed the function returns always true
-    fn granted(&self, message: &str, customer_id: &str) -> Result<bool, DatabaseError>;
+/// The TxAuthorization trait decides whether party one takes part in the second round of Sign. It is passed as rocket
+/// managed state independently of the [Db], so that the authorization policy can be swapped without touching storage
+#[async_trait]
+pub trait TxAuthorization: Send + Sync {
+    /// the granted function implements the logic of tx authorization. If no tx authorization is needed use [AllowAll].
+    /// A granted tx may be counted right away, e.g. towards spend limits, as it is released if it is not signed
+    async fn granted(&self, request: &TxAuthorizationRequest<'_>)
+        -> Result<TxDecision, DatabaseError>;
+    /// called when the signature of a tx granted by [TxAuthorization::granted] is not produced, e.g. to give back the
+    /// amount it reserved
+    async fn released(&self, _request: &TxAuthorizationRequest<'_>) -> Result<(), DatabaseError> {
+        Ok(())
+    }
+}
+
+/// The default [TxAuthorization] which grants every tx
+pub struct AllowAll;
+
+#[async_trait]
+impl TxAuthorization for AllowAll {
+    async fn granted(
+        &self,
+        _request: &TxAuthorizationRequest<'_>,
+    ) -> Result<TxDecision, DatabaseError> {
+        Ok(TxDecision::Granted)
+    }
+}
+
+/// The IncidentSink keeps the [SignatureIncident] of every signature that failed to verify, for later forensics
+#[async_trait]
+pub trait IncidentSink: Send + Sync {
+    async fn record(&self, incident: &SignatureIncident<'_>) -> Result<(), DatabaseError>;
+}
+
+/// The default [IncidentSink], which logs incidents as json
+pub struct LogIncidents;
+
+#[async_trait]
+impl IncidentSink for LogIncidents {
+    async fn record(&self, incident: &SignatureIncident<'_>) -> Result<(), DatabaseError> {
+        let incident = serde_json::to_string(incident).or(Err(DatabaseError::InsertError(-1)))?;
+        error!("Signature incident: {}", incident);
+        Ok(())
+    }
+}
+
+/// The IdGenerator assigns the ids of new keys and of cross-session signatures. Inject a [SeededIds] for reproducible
+/// transcripts in tests. The protocol messages themselves still draw from OS randomness, as two-party-ecdsa does not
+/// take an RNG
+pub trait IdGenerator: Send + Sync {
+    fn next_id(&self) -> String;
+}
+
+/// The default [IdGenerator] of random v4 UUIDs
+pub struct RandomIds;
+
+impl IdGenerator for RandomIds {
+    fn next_id(&self) -> String {
+        Uuid::new_v4().to_string()
+    }
+}
+
+/// A deterministic [IdGenerator]: the UUIDs of the seed followed by a counter, the same sequence for the same seed
+pub struct SeededIds {
+    seed: u64,
+    next: AtomicU64,
+}
+
+impl SeededIds {
+    pub fn new(seed: u64) -> Self {
+        SeededIds {
+            seed,
+            next: AtomicU64::new(0),
+        }
+    }
+}
+
+impl IdGenerator for SeededIds {
+    fn next_id(&self) -> String {
+        let counter = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut bytes = [0u8; 16];
+        bytes[..8].copy_from_slice(&self.seed.to_be_bytes());
+        bytes[8..].copy_from_slice(&counter.to_be_bytes());
+        Uuid::from_bytes(bytes).to_string()
+    }
+}
+
+/// The SessionStore keeps the ephemeral state of the cross-session (v2) Sign rounds, keyed by the ssid instead of
+/// the key id so that several signatures with the same key can run concurrently. Values expire after a TTL and are
+/// consumed at most once
+#[async_trait]
+pub trait SessionStore: Send + Sync {
+    ///insert a value which expires after `ttl`
+    async fn put(
+        &self,
+        key: &DbIndex,
+        table_name: &dyn MPCStruct,
+        value: &dyn Value,
+        ttl: Duration,
+    ) -> Result<(), DatabaseError>;
+    ///atomically get and delete a value. Concurrent calls for the same key get it at most once
+    async fn take(
+        &self,
+        key: &DbIndex,
+        table_name: &dyn MPCStruct,
+    ) -> Result<Option<Box<dyn Value>>, DatabaseError>;
+}
+
+/// The KeyEncryptionProvider wraps the per record data keys of [crate::encrypted_store::EncryptedDb] with a key
+/// encryption key (KEK) it never hands out, e.g. a local key file or a KMS. KEKs are versioned: new data keys are
+/// wrapped under the current version while older versions stay available to unwrap until every record is re-wrapped
+#[async_trait]
+pub trait KeyEncryptionProvider: Send + Sync {
+    ///the version of the KEK [KeyEncryptionProvider::wrap] uses
+    fn current_version(&self) -> u32;
+    ///encrypt a data key under the current KEK
+    async fn wrap(&self, data_key: &[u8]) -> Result<Vec<u8>, DatabaseError>;
+    ///decrypt a data key wrapped under the KEK `version`
+    async fn unwrap(&self, version: u32, wrapped_key: &[u8]) -> Result<Vec<u8>, DatabaseError>;
 }
 
-/// Common trait both for private and public for redis api
+/// Common trait both for private and public for redis api. Superseded by [crate::redis_store::RedisStore], which runs
+/// on a shared async connection and keeps the keys of customers apart
+#[deprecated(note = "use gotham_engine::redis_store::RedisStore")]
 pub trait RedisMod {
-    fn redis_get(key: String) -> RedisResult<String> {
-        let mut con = Self::redis_get_connection()?;
-        println!("[redis getting  key] {:?}", key);
-        let res = con.get(key)?;
-        Ok(res)
+    fn redis_get(redis_url: &str, key: String) -> RedisResult<String> {
+        let mut con = Self::redis_get_connection(redis_url)?;
+        debug!("Getting redis key {}", key);
+        con.get(key)
     }
 
-    fn redis_del(key: String) -> RedisResult<String> {
-        let mut con = Self::redis_get_connection()?;
-        println!("[redis deleting  key] {:?}", key);
-        let res: String = con.del(key)?;
-        Ok(res)
+    fn redis_del(redis_url: &str, key: String) -> RedisResult<String> {
+        let mut con = Self::redis_get_connection(redis_url)?;
+        debug!("Deleting redis key {}", key);
+        con.del(key)
     }
 
-    fn redis_set(key: String, value: String) -> RedisResult<String> {
-        let mut con = Self::redis_get_connection()?;
-        println!(
-            "[redis will write key - value ] = {:?}-{:?}",
-            key.clone(),
-            value.clone()
-        );
-        let res: String = con.set(key.clone(), value.clone())?;
-        println!(
-            "[redis wrote key - value ] = {:?}-{:?}",
-            key.clone(),
-            value.clone()
-        );
-        Ok(res)
+    fn redis_set(redis_url: &str, key: String, value: String) -> RedisResult<String> {
+        let mut con = Self::redis_get_connection(redis_url)?;
+        debug!("Setting redis key {}", key);
+        con.set(key, value)
     }
 
-    fn redis_get_connection() -> RedisResult<Connection> {
-        let redis_ip = env::var("ELASTICACHE_URL");
-        let redis = String::from("redis://");
-        let redis_url_var = String::from(redis + redis_ip.clone().unwrap().as_str());
-        println!("[redis connecting to] {:?}", redis_url_var);
-        let client = redis::Client::open(redis_url_var)?;
-        let info = client.get_connection_info();
-        println!("{:?}", info);
-        client.get_connection()
+    fn redis_get_connection(redis_url: &str) -> RedisResult<Connection> {
+        redis::Client::open(redis_url)?.get_connection()
     }
 }
 
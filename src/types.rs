@@ -1,12 +1,23 @@
 //! Common types for traits the implementations thereofs at [private_gotham] and [public_gotham]
 use crate::traits::MPCStruct;
+use rocket::http::Status;
+use rocket::request::Request;
+use rocket::response::{self, status::Custom, Responder};
+use rocket::serde::json::Json;
 use serde::{Deserialize, Serialize};
 use std::any::Any;
-use std::fmt::{Display, Formatter};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::fmt::{Debug, Display, Formatter};
+use std::str::FromStr;
 use thiserror::Error;
-use two_party_ecdsa::kms::ecdsa::two_party::party2;
-use two_party_ecdsa::party_one::Value;
-use two_party_ecdsa::BigInt;
+use tokio::sync::{Mutex, OwnedMutexGuard};
+use two_party_ecdsa::kms::ecdsa::two_party::{party1, party2};
+use two_party_ecdsa::kms::rotation::two_party::Rotation;
+use two_party_ecdsa::party_one::{self, Value};
+use two_party_ecdsa::party_two;
+use two_party_ecdsa::curv::arithmetic::traits::ZeroizeBN;
+use two_party_ecdsa::{BigInt, FE, GE};
 
 #[derive(Debug, Error, PartialEq, Eq, Clone)]
 /// The DatabaseError defines different types of database errors for better error handling
@@ -29,6 +40,86 @@ pub enum DatabaseError {
     /// Failed to delete a `(key, value)` pair into a table.
     #[error("Database delete error code: {0:?}")]
     ConfigError(i32),
+    /// Failed to encrypt or decrypt a value at rest.
+    #[error("Database encryption error code: {0:?}")]
+    EncryptionError(i32),
+}
+
+#[derive(Debug, Error)]
+/// The EngineError defines the failures of KeyGen and Sign. It is returned to clients as a json body
+/// `{"error": <kind>, "message": <description>}` with a matching http status
+pub enum EngineError {
+    /// The underlying Db failed
+    #[error("{0}")]
+    DbError(#[from] DatabaseError),
+    /// State expected from a previous round is not in the Db
+    #[error("No data for such identifier {0}")]
+    MissingState(String),
+    /// A proof or signature of the protocol did not verify
+    #[error("Verification failed: {0}")]
+    VerificationFailed(String),
+    /// The tx authorization policy denied the tx
+    #[error("Unauthorized transaction: {0}")]
+    Unauthorized(String),
+    /// The user has previously failed a signature verification and is blocked
+    #[error("Tainted user: {0}")]
+    Tainted(String),
+    /// The request can not be processed as is
+    #[error("Bad input: {0}")]
+    BadInput(String),
+    /// The customer already has an active share and new keygens are not allowed
+    #[error("Active share exists: {0}")]
+    ActiveShareExists(String),
+    /// A round was called before the previous one completed, or replayed
+    #[error("Out of order round: {0}")]
+    OutOfOrder(String),
+    /// Any other failure of the engine or its peripherals
+    #[error("Internal error: {0}")]
+    Internal(String),
+}
+
+#[derive(Serialize)]
+struct EngineErrorBody {
+    error: &'static str,
+    message: String,
+}
+
+impl EngineError {
+    /// A stable identifier of the error, for clients to react programmatically
+    pub fn kind(&self) -> &'static str {
+        match self {
+            EngineError::DbError(_) => "db_error",
+            EngineError::MissingState(_) => "missing_state",
+            EngineError::VerificationFailed(_) => "verification_failed",
+            EngineError::Unauthorized(_) => "unauthorized",
+            EngineError::Tainted(_) => "tainted",
+            EngineError::BadInput(_) => "bad_input",
+            EngineError::ActiveShareExists(_) => "active_share_exists",
+            EngineError::OutOfOrder(_) => "out_of_order",
+            EngineError::Internal(_) => "internal",
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        match self {
+            EngineError::DbError(_) | EngineError::Internal(_) => Status::InternalServerError,
+            EngineError::MissingState(_) => Status::NotFound,
+            EngineError::VerificationFailed(_) => Status::UnprocessableEntity,
+            EngineError::Unauthorized(_) | EngineError::Tainted(_) => Status::Forbidden,
+            EngineError::BadInput(_) => Status::BadRequest,
+            EngineError::ActiveShareExists(_) | EngineError::OutOfOrder(_) => Status::Conflict,
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for EngineError {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let body = EngineErrorBody {
+            error: self.kind(),
+            message: self.to_string(),
+        };
+        Custom(self.status(), Json(body)).respond_to(request)
+    }
 }
 
 /// The DbConnector indicates what type of DB will be used for storing the state during the Keyge, and sign interactive protocols
@@ -47,15 +138,89 @@ pub struct DbIndex {
     pub id: String,
 }
 
-/// The Authenticator indicates how the input requests to gotham server will be authorized. Currently there is the JWT option
-/// but in the future it will be discarded. Private gotham is using a jwt auth while public one does not use it
+/// The Authenticator indicates how the input requests to gotham server will be authorized. It is set through
+/// [crate::settings::EngineConfig::authenticator]. Private gotham is using a jwt auth while public one does not use it
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Authenticator {
-    /// passthrough mode to authentication at http level
+    /// passthrough mode to authentication at http level, the customer id is read from the `x-customer-id` header
     None,
-    /// verification with a valid JWT
+    /// verification with a valid JWT by the managed [crate::guarder::JwtVerifier]
     Jwt,
 }
 
+/// The format of the logs set up by [crate::telemetry::init], set through [crate::settings::EngineConfig::log_format]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// one json object per event, with the fields of its spans
+    Json,
+    /// human readable lines, for local runs
+    Text,
+}
+
+impl FromStr for Authenticator {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" | "" => Ok(Authenticator::None),
+            "jwt" => Ok(Authenticator::Jwt),
+            _ => Err(format!("Unknown authenticator {}", s)),
+        }
+    }
+}
+
+/// Per session (customerId, id) locks, managed as rocket state next to the [crate::traits::Db]. Rounds that read and
+/// write the state of the same session are serialized while different sessions run concurrently
+#[derive(Default)]
+pub struct SessionLocks {
+    locks: std::sync::Mutex<HashMap<(String, String), Arc<Mutex<()>>>>,
+}
+
+/// Holds the lock of a session until dropped
+pub struct SessionGuard<'a> {
+    locks: &'a SessionLocks,
+    key: (String, String),
+    lock: Arc<Mutex<()>>,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl SessionLocks {
+    pub async fn lock(&self, key: &DbIndex) -> SessionGuard<'_> {
+        let key = (key.customerId.clone(), key.id.clone());
+        let lock = self
+            .locks
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .entry(key.clone())
+            .or_default()
+            .clone();
+        let guard = lock.clone().lock_owned().await;
+        SessionGuard {
+            locks: self,
+            key,
+            lock,
+            guard: Some(guard),
+        }
+    }
+}
+
+impl Drop for SessionGuard<'_> {
+    fn drop(&mut self) {
+        self.guard.take();
+        let mut locks = self
+            .locks
+            .locks
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        // nobody else is holding or waiting for the lock: one reference in the map and ours
+        if Arc::strong_count(&self.lock) == 2 {
+            locks.remove(&self.key);
+        }
+    }
+}
+
 pub const CUSTOMER_ID_IDENTIFIER: &str = "customerId";
 pub const ID_IDENTIFIER: &str = "id";
 
@@ -86,9 +251,54 @@ pub enum EcdsaStruct {
 
     POS,
     Abort,
+
+    KeyGenState,
+
+    RotateCommit,
+    RotateRandom,
+    RotateFirstMsg,
+    RotatePrivateNew,
+    RotatePDLDecommit,
+    RotateAlpha,
+    RotateParty2PDLFirstMsg,
+    RotateParty1PDLFirstMsg,
+    RotationState,
 }
 
 impl EcdsaStruct {
+    /// All the tables used during KeyGen and Sign, e.g. for backends that need to create them upfront
+    pub const ALL: [EcdsaStruct; 29] = [
+        EcdsaStruct::KeyGenFirstMsg,
+        EcdsaStruct::CommWitness,
+        EcdsaStruct::EcKeyPair,
+        EcdsaStruct::PaillierKeyPair,
+        EcdsaStruct::Party1Private,
+        EcdsaStruct::Party2Public,
+        EcdsaStruct::PDLProver,
+        EcdsaStruct::PDLDecommit,
+        EcdsaStruct::Alpha,
+        EcdsaStruct::Party2PDLFirstMsg,
+        EcdsaStruct::CCKeyGenFirstMsg,
+        EcdsaStruct::CCCommWitness,
+        EcdsaStruct::CCEcKeyPair,
+        EcdsaStruct::CC,
+        EcdsaStruct::Party1MasterKey,
+        EcdsaStruct::EphEcKeyPair,
+        EcdsaStruct::EphKeyGenFirstMsg,
+        EcdsaStruct::POS,
+        EcdsaStruct::Abort,
+        EcdsaStruct::KeyGenState,
+        EcdsaStruct::RotateCommit,
+        EcdsaStruct::RotateRandom,
+        EcdsaStruct::RotateFirstMsg,
+        EcdsaStruct::RotatePrivateNew,
+        EcdsaStruct::RotatePDLDecommit,
+        EcdsaStruct::RotateAlpha,
+        EcdsaStruct::RotateParty2PDLFirstMsg,
+        EcdsaStruct::RotateParty1PDLFirstMsg,
+        EcdsaStruct::RotationState,
+    ];
+
     fn to_struct_name(&self) -> String {
         let res = match self {
             EcdsaStruct::KeyGenFirstMsg => "KeyGenFirstMsg",
@@ -109,7 +319,17 @@ impl EcdsaStruct {
             EcdsaStruct::EphEcKeyPair => "EphEcKeyPair",
             EcdsaStruct::EphKeyGenFirstMsg => "EphKeyGenFirstMsg",
             EcdsaStruct::POS => "POS",
-            EcdsaStruct::Abort => "v"
+            EcdsaStruct::Abort => "v",
+            EcdsaStruct::KeyGenState => "KeyGenState",
+            EcdsaStruct::RotateCommit => "RotateCommit",
+            EcdsaStruct::RotateRandom => "RotateRandom",
+            EcdsaStruct::RotateFirstMsg => "RotateFirstMsg",
+            EcdsaStruct::RotatePrivateNew => "Party1Private",
+            EcdsaStruct::RotatePDLDecommit => "PDLdecommit",
+            EcdsaStruct::RotateAlpha => "Alpha",
+            EcdsaStruct::RotateParty2PDLFirstMsg => "PDLFirstMessage",
+            EcdsaStruct::RotateParty1PDLFirstMsg => "RotatePDLFirstMsg",
+            EcdsaStruct::RotationState => "RotationState",
         };
 
         res.to_string()
@@ -119,14 +339,27 @@ impl EcdsaStruct {
 
 /// Wrapper struct for alpha values. They implement the Value trait in order to serialize/deserialize trait objects. Generics was not an option
 /// since they are used inside KeyGen and Sign traits which are treated as trait objects
-#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
 pub struct Alpha {
     pub value: BigInt,
 }
 
 impl Display for Alpha {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self)
+        write!(f, "Alpha")
+    }
+}
+
+/// Redacts the value, so that alpha can not end up in logs or assertion messages
+impl Debug for Alpha {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Alpha").finish_non_exhaustive()
+    }
+}
+
+impl Drop for Alpha {
+    fn drop(&mut self) {
+        self.value.zeroize_bn();
     }
 }
 
@@ -141,6 +374,178 @@ impl Value for Alpha {
     }
 }
 
+/// The rounds of KeyGen in the order they must complete
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum KeyGenStage {
+    FirstDone,
+    SecondDone,
+    ThirdDone,
+    FourthDone,
+    ChainCodeFirstDone,
+    MasterKeySet,
+}
+
+/// The last completed round of a KeyGen session. Each round of [crate::keygen::KeyGen] claims and advances it,
+/// so that rounds can be neither replayed nor called out of order, nor run concurrently
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct KeyGenState {
+    pub stage: KeyGenStage,
+    /// Unix timestamp in seconds of the first round, used to expire abandoned sessions
+    #[serde(default)]
+    pub started_at: i64,
+    /// Whether the round after [KeyGenState::stage] is running or failed
+    #[serde(default)]
+    pub in_progress: bool,
+}
+
+impl Display for KeyGenState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[typetag::serde]
+impl Value for KeyGenState {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn type_name(&self) -> &str {
+        "KeyGenState"
+    }
+}
+
+/// The rounds of [crate::rotate::Rotate] in the order they must complete. A new first round restarts the rotation
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum RotationStage {
+    /// Claimed by a first round, before it completes
+    Started,
+    FirstDone,
+    SecondDone,
+    ThirdDone,
+}
+
+/// The last completed round of an ongoing rotation, removed once the rotated master key is set. Each round of
+/// [crate::rotate::Rotate] claims and advances it like the [KeyGenState] of keygen
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct RotationState {
+    pub stage: RotationStage,
+    /// Unix timestamp in seconds of the first round, used to expire abandoned rotations
+    #[serde(default)]
+    pub started_at: i64,
+    /// Whether the round after [RotationState::stage] is running or failed
+    #[serde(default)]
+    pub in_progress: bool,
+}
+
+impl Display for RotationState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[typetag::serde]
+impl Value for RotationState {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn type_name(&self) -> &str {
+        "RotationState"
+    }
+}
+
+/// The secret commitment of party one to the coin flip of a rotation
+#[derive(Serialize, Deserialize)]
+pub struct RotateCommit {
+    pub m1: FE,
+    pub r1: FE,
+}
+
+impl Display for RotateCommit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "RotateCommit")
+    }
+}
+
+#[typetag::serde]
+impl Value for RotateCommit {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn type_name(&self) -> &str {
+        "RotateCommit"
+    }
+}
+
+/// The outcome of the coin flip of a rotation
+#[derive(Serialize, Deserialize)]
+pub struct RotateRandom {
+    pub value: Rotation,
+}
+
+impl Display for RotateRandom {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "RotateRandom")
+    }
+}
+
+#[typetag::serde]
+impl Value for RotateRandom {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn type_name(&self) -> &str {
+        "RotateRandom"
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RotateFirstMsg {
+    pub value: party1::RotationParty1Message1,
+}
+
+impl Display for RotateFirstMsg {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "RotateFirstMsg")
+    }
+}
+
+#[typetag::serde]
+impl Value for RotateFirstMsg {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn type_name(&self) -> &str {
+        "RotateFirstMsg"
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RotatePDLFirstMsg {
+    pub value: party_one::PDLFirstMessage,
+}
+
+impl Display for RotatePDLFirstMsg {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "RotatePDLFirstMsg")
+    }
+}
+
+#[typetag::serde]
+impl Value for RotatePDLFirstMsg {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn type_name(&self) -> &str {
+        "RotatePDLFirstMsg"
+    }
+}
+
 #[derive(Serialize, Deserialize,Debug)]
 pub(crate) struct Aborted {
     pub(crate) isAborted: String,
@@ -193,7 +598,84 @@ pub struct SignSecondMsgRequest {
     pub party_two_sign_message: party2::SignMessage,
     pub x_pos_child_key: BigInt,
     pub y_pos_child_key: BigInt,
+    /// Optional structured description of the tx, evaluated by the tx authorization policy
+    #[serde(default)]
+    pub tx_metadata: Option<TxMetadata>,
+}
+
+/// The forensic record of a signature that failed to verify, after which the key is tainted. It holds only public
+/// data, party two's messages and the public key, and never party one's share or ephemeral secrets
+#[derive(Serialize, Debug)]
+pub struct SignatureIncident<'a> {
+    pub customer_id: &'a str,
+    pub key_id: &'a str,
+    /// The ssid of a cross-session signature, the key id otherwise
+    pub ssid: &'a str,
+    /// Unix timestamp in seconds
+    pub occurred_at: i64,
+    /// The message hash party two asked to sign
+    pub message: &'a BigInt,
+    pub x_pos_child_key: &'a BigInt,
+    pub y_pos_child_key: &'a BigInt,
+    /// The joint public key of the child key
+    pub public: &'a GE,
+    pub party_two_sign_message: &'a party2::SignMessage,
+    pub eph_key_gen_first_message_party_two: &'a party_two::EphKeyGenFirstMsg,
+}
+
+/// A child key of the derivation path `[x_pos_child_key, y_pos_child_key]`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChildKey {
+    pub x_pos_child_key: BigInt,
+    pub y_pos_child_key: BigInt,
+    /// The joint public key of the child
+    pub public: GE,
+    /// Hex of the compressed [ChildKey::public]
+    pub public_key: String,
+}
+
+/// The public parts of a master key, for party two to cross-check against its own
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MasterPublicKey {
+    /// The joint public key
+    pub public: GE,
+    /// Hex of the compressed [MasterPublicKey::public]
+    pub public_key: String,
+    pub chain_code: BigInt,
+    /// The child key, when a derivation path was requested
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub child: Option<ChildKey>,
+}
+
+/// Structured metadata of the tx behind the signed message, as declared by the client
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct TxMetadata {
+    /// The amount transferred in the smallest unit of the asset
+    pub amount: Option<u64>,
+    pub destination: Option<String>,
+}
+/// The input of [crate::traits::TxAuthorization::granted] and [crate::traits::TxAuthorization::released]
+pub struct TxAuthorizationRequest<'a> {
+    pub customer_id: &'a str,
+    /// The id of the key as assigned during the first round of KeyGen
+    pub key_id: &'a str,
+    /// The hash of the tx to be signed
+    pub message: &'a BigInt,
+    /// The derivation path of the child key, `[x_pos_child_key, y_pos_child_key]`
+    pub child_path: &'a [BigInt],
+    pub metadata: Option<&'a TxMetadata>,
 }
+
+/// The outcome of [crate::traits::TxAuthorization::granted]
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum TxDecision {
+    Granted,
+    /// The reason is returned to the client in the [EngineError::Unauthorized] response
+    Denied(String),
+}
+
+/// `{user_id}_{id}_{name}`. Ids holding `_` make it ambiguous, e.g. user `a` with id `b_c` and user `a_b` with id `c`
+/// get the same string, so it must not key or authenticate values of different customers
 #[inline(always)]
 pub fn idify(user_id: &String, id: &String, name: &dyn MPCStruct) -> String {
     format!("{}_{}_{}", user_id, id, name.to_string())
@@ -0,0 +1,78 @@
+//! `cargo test --features test-utils`
+#![cfg(feature = "test-utils")]
+
+use gotham_engine::cleanup;
+use gotham_engine::test_utils;
+use gotham_engine::traits::Db;
+use gotham_engine::types::{DbIndex, EcdsaStruct};
+use two_party_ecdsa::curv::cryptographic_primitives::twoparty::coin_flip_optimal_rounds;
+
+use std::time::Duration;
+
+#[rocket::async_test]
+async fn intermediate_material_is_deleted() {
+    let customer_id = "customer";
+    let (db, client, id, _) = test_utils::keygen_in_memory(customer_id).await;
+    let complete = DbIndex {
+        customerId: customer_id.to_string(),
+        id,
+    };
+    for table in cleanup::INTERMEDIATE_KEYGEN_STRUCTS.iter() {
+        assert!(db.get(&complete, table).await.unwrap().is_none());
+    }
+    assert!(db
+        .get(&complete, &EcdsaStruct::KeyGenState)
+        .await
+        .unwrap()
+        .is_none());
+    assert!(db
+        .get(&complete, &EcdsaStruct::Party1MasterKey)
+        .await
+        .unwrap()
+        .is_some());
+
+    let id = test_utils::keygen_first(&client, customer_id).await;
+    let abandoned = DbIndex {
+        customerId: customer_id.to_string(),
+        id,
+    };
+
+    assert_eq!(cleanup::sweep(db.as_ref(), Duration::from_secs(3600)).await, Ok(0));
+    assert_eq!(cleanup::sweep(db.as_ref(), Duration::ZERO).await, Ok(1));
+    for table in [EcdsaStruct::CommWitness, EcdsaStruct::EcKeyPair, EcdsaStruct::KeyGenState] {
+        assert!(db.get(&abandoned, &table).await.unwrap().is_none());
+    }
+    assert!(db
+        .get(&complete, &EcdsaStruct::Party1MasterKey)
+        .await
+        .unwrap()
+        .is_some());
+}
+
+#[rocket::async_test]
+async fn abandoned_rotations_are_swept() {
+    let customer_id = "customer";
+    let (db, client, id, _) = test_utils::keygen_in_memory(customer_id).await;
+    let _: coin_flip_optimal_rounds::Party1FirstMessage =
+        test_utils::post(&client, customer_id, format!("/ecdsa/rotate/{}/first", id), &()).await;
+    let key = DbIndex {
+        customerId: customer_id.to_string(),
+        id,
+    };
+    assert!(db
+        .get(&key, &EcdsaStruct::RotateCommit)
+        .await
+        .unwrap()
+        .is_some());
+
+    assert_eq!(cleanup::sweep(db.as_ref(), Duration::from_secs(3600)).await, Ok(0));
+    assert_eq!(cleanup::sweep(db.as_ref(), Duration::ZERO).await, Ok(1));
+    for table in cleanup::ROTATION_STRUCTS.iter() {
+        assert!(db.get(&key, table).await.unwrap().is_none());
+    }
+    assert!(db
+        .get(&key, &EcdsaStruct::Party1MasterKey)
+        .await
+        .unwrap()
+        .is_some());
+}
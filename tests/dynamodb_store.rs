@@ -0,0 +1,32 @@
+//! Runs against DynamoDB Local, e.g. `docker run -p 8000:8000 amazon/dynamodb-local` and
+//! `DYNAMODB_LOCAL_ENDPOINT=http://localhost:8000 cargo test --features rusoto_dynamodb -- --ignored`
+#![cfg(feature = "rusoto_dynamodb")]
+
+use gotham_engine::dynamodb_store::DynamoDbStore;
+use gotham_engine::traits::Db;
+use gotham_engine::types::{Alpha, DbIndex, EcdsaStruct};
+use two_party_ecdsa::BigInt;
+
+#[tokio::test]
+#[ignore]
+async fn insert_get_roundtrip() {
+    let endpoint = std::env::var("DYNAMODB_LOCAL_ENDPOINT")
+        .unwrap_or_else(|_| "http://localhost:8000".to_string());
+    let store = DynamoDbStore::with_endpoint(&endpoint, "test");
+    store.create_tables().await.unwrap();
+
+    let key = DbIndex {
+        customerId: uuid::Uuid::new_v4().to_string(),
+        id: uuid::Uuid::new_v4().to_string(),
+    };
+    assert!(store.get(&key, &EcdsaStruct::Alpha).await.unwrap().is_none());
+
+    let alpha = Alpha {
+        value: BigInt::from(42),
+    };
+    store.insert(&key, &EcdsaStruct::Alpha, &alpha).await.unwrap();
+    let value = store.get(&key, &EcdsaStruct::Alpha).await.unwrap().unwrap();
+    assert_eq!(value.as_any().downcast_ref::<Alpha>().unwrap(), &alpha);
+
+    assert!(!store.has_active_share(&key.customerId).await.unwrap());
+}
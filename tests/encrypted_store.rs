@@ -0,0 +1,223 @@
+//! `cargo test --features test-utils`
+#![cfg(feature = "test-utils")]
+
+use gotham_engine::encrypted_store::{self, EncryptedDb, LocalKek, Sealed};
+use gotham_engine::memory_store::InMemoryStore;
+use gotham_engine::test_utils;
+use gotham_engine::traits::{Db, SessionStore};
+use gotham_engine::types::{Alpha, DatabaseError, DbIndex, EcdsaStruct};
+use two_party_ecdsa::kms::ecdsa::two_party::{MasterKey1, MasterKey2};
+use two_party_ecdsa::party_one::{self, HDPos};
+use two_party_ecdsa::BigInt;
+
+use std::sync::Arc;
+
+#[rocket::async_test]
+async fn secrets_are_sealed_at_rest() {
+    let db = Arc::new(EncryptedDb::new(
+        InMemoryStore::default(),
+        Arc::new(LocalKek::new(&[7u8; 32])),
+    ));
+    test_utils::certify(db.clone()).await;
+
+    let client = test_utils::client(db.clone()).await;
+    let customer_id = "customer";
+    let (id, _) = test_utils::keygen(&client, customer_id).await;
+    let key = DbIndex {
+        customerId: customer_id.to_string(),
+        id,
+    };
+
+    let stored = db
+        .inner()
+        .get(&key, &EcdsaStruct::Party1MasterKey)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(stored.as_any().downcast_ref::<Sealed>().is_some());
+    let stored = db.inner().get(&key, &EcdsaStruct::POS).await.unwrap().unwrap();
+    assert!(stored.as_any().downcast_ref::<HDPos>().is_some());
+
+    let master_key = db
+        .get(&key, &EcdsaStruct::Party1MasterKey)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(master_key.as_any().downcast_ref::<MasterKey1>().is_some());
+
+    //a sealed value moved to another key does not open
+    let sealed = db
+        .inner()
+        .get(&key, &EcdsaStruct::Party1MasterKey)
+        .await
+        .unwrap()
+        .unwrap();
+    let other = DbIndex {
+        customerId: "other".to_string(),
+        id: key.id.clone(),
+    };
+    db.inner()
+        .insert(&other, &EcdsaStruct::Party1MasterKey, sealed.as_ref())
+        .await
+        .unwrap();
+    assert!(db.get(&other, &EcdsaStruct::Party1MasterKey).await.is_err());
+
+    //nor under another KEK
+    let other_kek = EncryptedDb::new(
+        InMemoryStore::default(),
+        Arc::new(LocalKek::new(&[8u8; 32])),
+    );
+    other_kek
+        .inner()
+        .insert(&key, &EcdsaStruct::Party1MasterKey, sealed.as_ref())
+        .await
+        .unwrap();
+    assert!(other_kek
+        .get(&key, &EcdsaStruct::Party1MasterKey)
+        .await
+        .is_err());
+}
+
+#[rocket::async_test]
+async fn sealed_values_open_under_their_own_key_only() {
+    let db = EncryptedDb::new(
+        InMemoryStore::default(),
+        Arc::new(LocalKek::new(&[7u8; 32])),
+    );
+    let key = DbIndex {
+        customerId: "a_b".to_string(),
+        id: "c".to_string(),
+    };
+    db.insert(
+        &key,
+        &EcdsaStruct::Alpha,
+        &Alpha {
+            value: BigInt::from(42),
+        },
+    )
+    .await
+    .unwrap();
+    let sealed = db.inner().get(&key, &EcdsaStruct::Alpha).await.unwrap().unwrap();
+
+    // `a`, `b_c` and `a_b`, `c` are both `a_b_c` to idify
+    for (customer_id, id) in [("a", "b_c"), ("a_b_c", ""), ("other", "c"), ("a_b", "d")] {
+        let other = DbIndex {
+            customerId: customer_id.to_string(),
+            id: id.to_string(),
+        };
+        db.inner()
+            .insert(&other, &EcdsaStruct::Alpha, sealed.as_ref())
+            .await
+            .unwrap();
+        assert_eq!(
+            db.get(&other, &EcdsaStruct::Alpha).await.err(),
+            Some(DatabaseError::EncryptionError(-1))
+        );
+    }
+    db.inner()
+        .insert(&key, &EcdsaStruct::RotateAlpha, sealed.as_ref())
+        .await
+        .unwrap();
+    assert!(db.get(&key, &EcdsaStruct::RotateAlpha).await.is_err());
+}
+
+#[rocket::async_test]
+async fn ephemeral_keys_are_sealed_at_rest() {
+    let sessions = Arc::new(EncryptedDb::new(
+        InMemoryStore::default(),
+        Arc::new(LocalKek::new(&[7u8; 32])),
+    ));
+    let client =
+        test_utils::client_with_sessions(Arc::new(InMemoryStore::default()), sessions.clone())
+            .await;
+    let customer_id = "customer";
+    let (id, master_key) = test_utils::keygen(&client, customer_id).await;
+
+    //the ephemeral keys open again for the second round
+    let message = BigInt::from(1234);
+    let signature = test_utils::sign(
+        &client,
+        customer_id,
+        &id,
+        &master_key,
+        BigInt::from(0),
+        BigInt::from(0),
+        &message,
+    )
+    .await;
+    let child_master_key = master_key.get_child(vec![BigInt::from(0), BigInt::from(0)]);
+    party_one::verify(
+        &party_one::Signature {
+            r: signature.r,
+            s: signature.s,
+        },
+        &child_master_key.public.q,
+        &message,
+    )
+    .expect("a valid signature");
+
+    let (eph_key_gen_first_message_party_two, _, _) = MasterKey2::sign_first_message();
+    let _: party_one::EphKeyGenFirstMsg = test_utils::post(
+        &client,
+        customer_id,
+        format!("/ecdsa/sign/{}/first", id),
+        &eph_key_gen_first_message_party_two,
+    )
+    .await;
+    let stored = sessions
+        .inner()
+        .take(
+            &DbIndex {
+                customerId: customer_id.to_string(),
+                id,
+            },
+            &EcdsaStruct::EphEcKeyPair,
+        )
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(stored.as_any().downcast_ref::<Sealed>().is_some());
+}
+
+#[rocket::async_test]
+async fn rewrap_moves_records_to_the_current_kek() {
+    let db = EncryptedDb::new(
+        InMemoryStore::default(),
+        Arc::new(LocalKek::new(&[1u8; 32])),
+    );
+    let sealed = DbIndex {
+        customerId: "customer".to_string(),
+        id: "sealed".to_string(),
+    };
+    let plaintext = DbIndex {
+        customerId: "customer".to_string(),
+        id: "plaintext".to_string(),
+    };
+    let alpha = Alpha {
+        value: BigInt::from(42),
+    };
+    db.insert(&sealed, &EcdsaStruct::Alpha, &alpha).await.unwrap();
+    db.inner()
+        .insert(&plaintext, &EcdsaStruct::Alpha, &alpha)
+        .await
+        .unwrap();
+
+    //both versions are readable until the migration completes
+    let db = EncryptedDb::new(
+        db.into_inner(),
+        Arc::new(LocalKek::new(&[1u8; 32]).with_kek(1, &[2u8; 32])),
+    );
+    for key in [&sealed, &plaintext] {
+        let value = db.get(key, &EcdsaStruct::Alpha).await.unwrap().unwrap();
+        assert_eq!(value.as_any().downcast_ref::<Alpha>().unwrap().value, alpha.value);
+    }
+
+    assert_eq!(encrypted_store::rewrap(&db).await, Ok(2));
+    assert_eq!(encrypted_store::rewrap(&db).await, Ok(0));
+    for key in [&sealed, &plaintext] {
+        let stored = db.inner().get(key, &EcdsaStruct::Alpha).await.unwrap().unwrap();
+        assert_eq!(stored.as_any().downcast_ref::<Sealed>().unwrap().kek_version, 1);
+        let value = db.get(key, &EcdsaStruct::Alpha).await.unwrap().unwrap();
+        assert_eq!(value.as_any().downcast_ref::<Alpha>().unwrap().value, alpha.value);
+    }
+}
@@ -0,0 +1,122 @@
+//! The fixtures are a throwaway RSA key, `jwt_rs256.pem`, and its public half as `jwks.json` under the kid `test-key`
+use gotham_engine::guarder::{Claims, JwtVerifier};
+use gotham_engine::settings::EngineConfig;
+use gotham_engine::types::Authenticator;
+
+use chrono::Utc;
+use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+use rocket::http::{Header as HttpHeader, Status};
+use rocket::local::asynchronous::Client;
+use rocket::{get, routes};
+use serde_json::json;
+
+const JWKS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/jwks.json");
+const ISSUER: &str = "https://issuer.example";
+const AUDIENCE: &str = "gotham";
+
+fn token(issuer: &str, audience: &str, expires_in: i64) -> String {
+    let mut header = Header::new(Algorithm::RS256);
+    header.kid = Some("test-key".to_string());
+    let key = EncodingKey::from_rsa_pem(include_bytes!("fixtures/jwt_rs256.pem")).unwrap();
+    encode(
+        &header,
+        &json!({
+            "sub": "customer",
+            "iss": issuer,
+            "aud": audience,
+            "exp": Utc::now().timestamp() + expires_in,
+        }),
+        &key,
+    )
+    .unwrap()
+}
+
+fn verifier() -> JwtVerifier {
+    JwtVerifier::from_jwks_file(JWKS, ISSUER, AUDIENCE).unwrap()
+}
+
+#[test]
+fn valid_tokens_are_verified() {
+    let claims = verifier().verify(&token(ISSUER, AUDIENCE, 600)).unwrap();
+    assert_eq!(claims.sub, "customer");
+}
+
+#[test]
+fn invalid_tokens_are_rejected() {
+    let verifier = verifier();
+    // past the default leeway of 60 seconds
+    assert!(verifier.verify(&token(ISSUER, AUDIENCE, -3600)).is_err());
+    assert!(verifier.verify(&token(ISSUER, "other", 600)).is_err());
+    assert!(verifier.verify(&token("https://other.example", AUDIENCE, 600)).is_err());
+
+    let token = token(ISSUER, AUDIENCE, 600);
+    let (signed, signature) = token.rsplit_once('.').unwrap();
+    let mut signature = signature.as_bytes().to_vec();
+    signature[0] = if signature[0] == b'A' { b'B' } else { b'A' };
+    let tampered = format!("{}.{}", signed, String::from_utf8(signature).unwrap());
+    assert!(verifier.verify(&tampered).is_err());
+}
+
+#[get("/whoami")]
+fn whoami(claims: Claims) -> String {
+    claims.sub
+}
+
+async fn client(config: Option<EngineConfig>) -> Client {
+    let mut rocket = rocket::build()
+        .manage(verifier())
+        .mount("/", routes![whoami]);
+    if let Some(config) = config {
+        rocket = rocket.manage(config);
+    }
+    Client::tracked(rocket).await.unwrap()
+}
+
+#[rocket::async_test]
+async fn the_guard_verifies_bearer_tokens() {
+    let client = client(Some(EngineConfig {
+        authenticator: Authenticator::Jwt,
+        ..Default::default()
+    }))
+    .await;
+
+    let response = client
+        .get("/whoami")
+        .header(HttpHeader::new(
+            "Authorization",
+            format!("Bearer {}", token(ISSUER, AUDIENCE, 600)),
+        ))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_string().await.unwrap(), "customer");
+
+    let expired = client
+        .get("/whoami")
+        .header(HttpHeader::new(
+            "Authorization",
+            format!("Bearer {}", token(ISSUER, AUDIENCE, -3600)),
+        ))
+        .dispatch()
+        .await;
+    assert_eq!(expired.status(), Status::Unauthorized);
+
+    // the passthrough header is not trusted in jwt mode
+    let header_only = client
+        .get("/whoami")
+        .header(HttpHeader::new("x-customer-id", "customer"))
+        .dispatch()
+        .await;
+    assert_eq!(header_only.status(), Status::Unauthorized);
+}
+
+#[rocket::async_test]
+async fn the_guard_fails_closed_without_a_config() {
+    let client = client(None).await;
+    let response = client
+        .get("/whoami")
+        .header(HttpHeader::new("x-customer-id", "customer"))
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::InternalServerError);
+}
@@ -0,0 +1,152 @@
+//! `cargo test --features test-utils`
+#![cfg(feature = "test-utils")]
+
+use gotham_engine::memory_store::InMemoryStore;
+use gotham_engine::test_utils;
+use gotham_engine::traits::{Db, SessionStore};
+use gotham_engine::types::{DbIndex, EcdsaStruct, SignSecondMsgRequest};
+use two_party_ecdsa::kms::ecdsa::two_party::{MasterKey1, MasterKey2};
+use two_party_ecdsa::{party_one, BigInt};
+
+use log::{LevelFilter, Log, Metadata, Record};
+use rocket::http::Status;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+static LOGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+struct Capture;
+
+impl Log for Capture {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        LOGS.lock().unwrap().push(format!("{}", record.args()));
+    }
+
+    fn flush(&self) {}
+}
+
+/// The string leaves of a json value, e.g. the hex of the scalars of a secret share
+fn leaves(value: &serde_json::Value, out: &mut Vec<String>) {
+    match value {
+        serde_json::Value::String(leaf) => out.push(leaf.clone()),
+        serde_json::Value::Array(values) => values.iter().for_each(|value| leaves(value, out)),
+        serde_json::Value::Object(values) => values.values().for_each(|value| leaves(value, out)),
+        _ => {}
+    }
+}
+
+#[rocket::async_test]
+async fn failed_signatures_record_no_secrets() {
+    log::set_logger(&Capture).unwrap();
+    log::set_max_level(LevelFilter::Trace);
+
+    let db: Arc<dyn Db> = Arc::new(InMemoryStore::default());
+    let sessions = Arc::new(InMemoryStore::default());
+    let incidents = Arc::new(InMemoryStore::default());
+    let client =
+        test_utils::client_with_incidents(db.clone(), sessions.clone(), incidents.clone()).await;
+    let customer_id = "customer";
+    let (id, master_key) = test_utils::keygen(&client, customer_id).await;
+
+    let (x_pos, y_pos) = (BigInt::from(0), BigInt::from(0));
+    let child_master_key = master_key.get_child(vec![x_pos.clone(), y_pos.clone()]);
+    let (eph_key_gen_first_message_party_two, eph_comm_witness, eph_ec_key_pair_party2) =
+        MasterKey2::sign_first_message();
+    let (ssid, sign_party_one_first_message): (String, party_one::EphKeyGenFirstMsg) =
+        test_utils::post(
+            &client,
+            customer_id,
+            format!("/ecdsa/sign/{}/first_v2", id),
+            &eph_key_gen_first_message_party_two,
+        )
+        .await;
+
+    //party one's ephemeral key of the session, put back for the second round
+    let session = DbIndex {
+        customerId: customer_id.to_string(),
+        id: ssid.clone(),
+    };
+    let eph_ec_key_pair_party1 = sessions
+        .take(&session, &EcdsaStruct::EphEcKeyPair)
+        .await
+        .unwrap()
+        .unwrap();
+    sessions
+        .put(
+            &session,
+            &EcdsaStruct::EphEcKeyPair,
+            eph_ec_key_pair_party1.as_ref(),
+            Duration::from_secs(300),
+        )
+        .await
+        .unwrap();
+    let eph_ec_key_pair_party1 = eph_ec_key_pair_party1
+        .as_any()
+        .downcast_ref::<party_one::EphEcKeyPair>()
+        .unwrap();
+
+    //party two signs another message than the one it asks for
+    let party_two_sign_message = child_master_key.sign_second_message(
+        &eph_ec_key_pair_party2,
+        eph_comm_witness,
+        &sign_party_one_first_message,
+        &BigInt::from(1234),
+    );
+    let response = test_utils::post_as(
+        &client,
+        customer_id,
+        format!("/ecdsa/sign/{}/second_v2", ssid),
+        &SignSecondMsgRequest {
+            message: BigInt::from(5678),
+            party_two_sign_message,
+            x_pos_child_key: x_pos,
+            y_pos_child_key: y_pos,
+            tx_metadata: None,
+        },
+    )
+    .await;
+    assert_eq!(response.status(), Status::UnprocessableEntity);
+
+    let recorded = incidents.incidents();
+    assert_eq!(recorded.len(), 1);
+    assert!(recorded[0].contains(&ssid));
+
+    let party_one_master_key = db
+        .get(
+            &DbIndex {
+                customerId: customer_id.to_string(),
+                id,
+            },
+            &EcdsaStruct::Party1MasterKey,
+        )
+        .await
+        .unwrap()
+        .unwrap();
+    let party_one_master_key = party_one_master_key
+        .as_any()
+        .downcast_ref::<MasterKey1>()
+        .unwrap();
+    let party_one_master_key = serde_json::to_value(party_one_master_key).unwrap();
+    let mut secrets = Vec::new();
+    leaves(&party_one_master_key["private"], &mut secrets);
+    secrets.retain(|secret| secret.len() >= 16);
+    assert!(!secrets.is_empty());
+    let mut ephemeral_secrets = Vec::new();
+    leaves(
+        &serde_json::to_value(eph_ec_key_pair_party1).unwrap()["secret_share"],
+        &mut ephemeral_secrets,
+    );
+    ephemeral_secrets.retain(|secret| secret.len() >= 16);
+    assert!(!ephemeral_secrets.is_empty());
+    secrets.extend(ephemeral_secrets);
+
+    let logs = LOGS.lock().unwrap();
+    for secret in secrets.iter() {
+        assert!(!recorded[0].contains(secret.as_str()));
+        assert!(logs.iter().all(|line| !line.contains(secret.as_str())));
+    }
+}
@@ -0,0 +1,433 @@
+//! `cargo test --features test-utils`
+#![cfg(feature = "test-utils")]
+
+use gotham_engine::hd::compressed_public_key;
+use gotham_engine::memory_store::InMemoryStore;
+use gotham_engine::policy::{Policy, PolicyEngine, PolicySet};
+use gotham_engine::settings::EngineConfig;
+use gotham_engine::test_utils;
+use gotham_engine::traits::{Db, SeededIds};
+use gotham_engine::types::{
+    ChildKey, DbIndex, EcdsaStruct, KeyGenState, MasterPublicKey, RotationStage, RotationState,
+    SignSecondMsgRequest, TxMetadata,
+};
+use two_party_ecdsa::curv::cryptographic_primitives::twoparty::coin_flip_optimal_rounds;
+use two_party_ecdsa::kms::ecdsa::two_party::MasterKey2;
+use two_party_ecdsa::kms::rotation::two_party::party2::Rotation2;
+use two_party_ecdsa::{party_one, BigInt};
+
+use rocket::http::Status;
+use std::sync::Arc;
+
+#[rocket::async_test]
+async fn keygen_and_sign_in_memory() {
+    test_utils::certify(Arc::new(InMemoryStore::default())).await;
+}
+
+#[rocket::async_test]
+async fn keygen_rounds_are_ordered() {
+    let customer_id = "customer";
+    let (_, client, id, _) = test_utils::keygen_in_memory(customer_id).await;
+
+    let (kg_party_two_first_message, _) = MasterKey2::key_gen_first_message();
+    let replay = test_utils::post_as(
+        &client,
+        customer_id,
+        format!("/ecdsa/keygen/{}/second", id),
+        &kg_party_two_first_message.d_log_proof,
+    )
+    .await;
+    assert_eq!(replay.status(), Status::Conflict);
+
+    let id = test_utils::keygen_first(&client, customer_id).await;
+    let early = test_utils::post_as(
+        &client,
+        customer_id,
+        format!("/ecdsa/keygen/{}/chaincode/first", id),
+        &(),
+    )
+    .await;
+    assert_eq!(early.status(), Status::Conflict);
+}
+
+#[rocket::async_test]
+async fn keygen_rounds_claimed_elsewhere_conflict() {
+    let db: Arc<dyn Db> = Arc::new(InMemoryStore::default());
+    let client = test_utils::client(db.clone()).await;
+    let customer_id = "customer";
+
+    let id = test_utils::keygen_first(&client, customer_id).await;
+    let key = DbIndex {
+        customerId: customer_id.to_string(),
+        id: id.clone(),
+    };
+
+    // another engine instance claimed the second round
+    let current = db.get(&key, &EcdsaStruct::KeyGenState).await.unwrap().unwrap();
+    let state = current.as_any().downcast_ref::<KeyGenState>().unwrap().clone();
+    let claimed = KeyGenState {
+        in_progress: true,
+        ..state
+    };
+    assert!(db
+        .compare_and_swap(&key, &EcdsaStruct::KeyGenState, Some(current.as_ref()), &claimed)
+        .await
+        .unwrap());
+
+    let (kg_party_two_first_message, _) = MasterKey2::key_gen_first_message();
+    let second = test_utils::post_as(
+        &client,
+        customer_id,
+        format!("/ecdsa/keygen/{}/second", id),
+        &kg_party_two_first_message.d_log_proof,
+    )
+    .await;
+    assert_eq!(second.status(), Status::Conflict);
+}
+
+#[rocket::async_test]
+async fn ephemeral_keys_sign_once() {
+    let customer_id = "customer";
+    let (_, client, id, master_key) = test_utils::keygen_in_memory(customer_id).await;
+    let (x_pos, y_pos, message) = (BigInt::from(0), BigInt::from(0), BigInt::from(1234));
+    let child_master_key = master_key.get_child(vec![x_pos.clone(), y_pos.clone()]);
+
+    let (eph_key_gen_first_message_party_two, eph_comm_witness, eph_ec_key_pair_party2) =
+        MasterKey2::sign_first_message();
+    let sign_party_one_first_message: party_one::EphKeyGenFirstMsg = test_utils::post(
+        &client,
+        customer_id,
+        format!("/ecdsa/sign/{}/first", id),
+        &eph_key_gen_first_message_party_two,
+    )
+    .await;
+    let request = serde_json::to_value(SignSecondMsgRequest {
+        message: message.clone(),
+        party_two_sign_message: child_master_key.sign_second_message(
+            &eph_ec_key_pair_party2,
+            eph_comm_witness,
+            &sign_party_one_first_message,
+            &message,
+        ),
+        x_pos_child_key: x_pos,
+        y_pos_child_key: y_pos,
+        tx_metadata: None,
+    })
+    .unwrap();
+
+    for expected in [Status::Ok, Status::NotFound] {
+        let response = test_utils::post_as(
+            &client,
+            customer_id,
+            format!("/ecdsa/sign/{}/second", id),
+            &request,
+        )
+        .await;
+        assert_eq!(response.status(), expected);
+    }
+}
+
+#[rocket::async_test]
+async fn ssids_sign_once() {
+    let customer_id = "customer";
+    let (_, client, id, master_key) = test_utils::keygen_in_memory(customer_id).await;
+    let (x_pos, y_pos, message) = (BigInt::from(0), BigInt::from(0), BigInt::from(1234));
+    let child_master_key = master_key.get_child(vec![x_pos.clone(), y_pos.clone()]);
+
+    let (eph_key_gen_first_message_party_two, eph_comm_witness, eph_ec_key_pair_party2) =
+        MasterKey2::sign_first_message();
+    let (ssid, sign_party_one_first_message): (String, party_one::EphKeyGenFirstMsg) =
+        test_utils::post(
+            &client,
+            customer_id,
+            format!("/ecdsa/sign/{}/first_v2", id),
+            &eph_key_gen_first_message_party_two,
+        )
+        .await;
+    let request = SignSecondMsgRequest {
+        message: message.clone(),
+        party_two_sign_message: child_master_key.sign_second_message(
+            &eph_ec_key_pair_party2,
+            eph_comm_witness,
+            &sign_party_one_first_message,
+            &message,
+        ),
+        x_pos_child_key: x_pos,
+        y_pos_child_key: y_pos,
+        tx_metadata: None,
+    };
+
+    for expected in [Status::Ok, Status::NotFound] {
+        let response = test_utils::post_as(
+            &client,
+            customer_id,
+            format!("/ecdsa/sign/{}/second_v2", ssid),
+            &request,
+        )
+        .await;
+        assert_eq!(response.status(), expected);
+    }
+}
+
+#[rocket::async_test]
+async fn policy_limits_hold_for_concurrent_signs() {
+    let policy = PolicyEngine::new(PolicySet {
+        default: Policy {
+            daily_limit: Some(100),
+            ..Default::default()
+        },
+        customers: vec![],
+    })
+    .unwrap();
+    let client =
+        test_utils::client_with_authorizer(Arc::new(InMemoryStore::default()), Arc::new(policy))
+            .await;
+    let customer_id = "customer";
+    let message = BigInt::from(1234);
+
+    let mut requests = vec![];
+    for _ in 0..2 {
+        let (id, master_key) = test_utils::keygen(&client, customer_id).await;
+        let mut request = test_utils::sign_first(
+            &client,
+            customer_id,
+            &id,
+            &master_key,
+            BigInt::from(0),
+            BigInt::from(0),
+            &message,
+        )
+        .await;
+        request.tx_metadata = Some(TxMetadata {
+            amount: Some(60),
+            destination: None,
+        });
+        requests.push((format!("/ecdsa/sign/{}/second", id), request));
+    }
+
+    // granted, then failing on the unknown key, which releases the amount
+    let unknown = test_utils::post_as(
+        &client,
+        customer_id,
+        "/ecdsa/sign/unknown/second".to_string(),
+        &requests[0].1,
+    )
+    .await;
+    assert_eq!(unknown.status(), Status::NotFound);
+
+    // keys are locked one by one, only the policy keeps both from spending 120 of 100
+    let (first, second) = tokio::join!(
+        test_utils::post_as(&client, customer_id, requests[0].0.clone(), &requests[0].1),
+        test_utils::post_as(&client, customer_id, requests[1].0.clone(), &requests[1].1),
+    );
+    let mut statuses = [first.status().code, second.status().code];
+    statuses.sort();
+    assert_eq!(statuses, [Status::Ok.code, Status::Forbidden.code]);
+}
+
+#[rocket::async_test]
+async fn rotation_keeps_the_public_key() {
+    let customer_id = "customer";
+    let (_, client, id, master_key) = test_utils::keygen_in_memory(customer_id).await;
+
+    let rotated = test_utils::rotate(&client, customer_id, &id, &master_key).await;
+    assert_eq!(rotated.public.q, master_key.public.q);
+
+    let (x_pos, y_pos, message) = (BigInt::from(0), BigInt::from(0), BigInt::from(1234));
+    let child_master_key = master_key.get_child(vec![x_pos.clone(), y_pos.clone()]);
+    let signature =
+        test_utils::sign(&client, customer_id, &id, &rotated, x_pos, y_pos, &message).await;
+    party_one::verify(
+        &party_one::Signature {
+            r: signature.r,
+            s: signature.s,
+        },
+        &child_master_key.public.q,
+        &message,
+    )
+    .expect("the rotated shares sign for the same public key");
+}
+
+#[rocket::async_test]
+async fn rotation_rounds_claimed_elsewhere_conflict() {
+    let customer_id = "customer";
+    let (db, client, id, _) = test_utils::keygen_in_memory(customer_id).await;
+    let key = DbIndex {
+        customerId: customer_id.to_string(),
+        id: id.clone(),
+    };
+    let coin_flip_party1_first_message: coin_flip_optimal_rounds::Party1FirstMessage =
+        test_utils::post(&client, customer_id, format!("/ecdsa/rotate/{}/first", id), &()).await;
+
+    // another engine instance claimed the second round
+    let current = db.get(&key, &EcdsaStruct::RotationState).await.unwrap().unwrap();
+    let state = current.as_any().downcast_ref::<RotationState>().unwrap().clone();
+    assert_eq!(state.stage, RotationStage::FirstDone);
+    let claimed = RotationState {
+        in_progress: true,
+        ..state
+    };
+    assert!(db
+        .compare_and_swap(&key, &EcdsaStruct::RotationState, Some(current.as_ref()), &claimed)
+        .await
+        .unwrap());
+
+    let second = test_utils::post_as(
+        &client,
+        customer_id,
+        format!("/ecdsa/rotate/{}/second", id),
+        &Rotation2::key_rotate_first_message(&coin_flip_party1_first_message),
+    )
+    .await;
+    assert_eq!(second.status(), Status::Conflict);
+    // nor does a new rotation start over the running round
+    let restart =
+        test_utils::post_as(&client, customer_id, format!("/ecdsa/rotate/{}/first", id), &()).await;
+    assert_eq!(restart.status(), Status::Conflict);
+}
+
+#[rocket::async_test]
+async fn tainted_keys_are_not_rotated() {
+    let customer_id = "customer";
+    let (db, client, id, _) = test_utils::keygen_in_memory(customer_id).await;
+
+    db.insert(
+        &DbIndex {
+            customerId: customer_id.to_string(),
+            id: id.clone(),
+        },
+        &EcdsaStruct::Abort,
+        &party_one::v {
+            value: "true".to_string(),
+        },
+    )
+    .await
+    .unwrap();
+    let rotation = test_utils::post_as(
+        &client,
+        customer_id,
+        format!("/ecdsa/rotate/{}/first", id),
+        &(),
+    )
+    .await;
+    assert_eq!(rotation.status(), Status::Forbidden);
+}
+
+#[rocket::async_test]
+async fn child_positions_are_allocated_once() {
+    let customer_id = "customer";
+    let (_, client, id, master_key) = test_utils::keygen_in_memory(customer_id).await;
+
+    for y_pos in 0..2 {
+        let child: ChildKey =
+            test_utils::post(&client, customer_id, format!("/ecdsa/{}/child", id), &()).await;
+        assert_eq!(child.x_pos_child_key, BigInt::from(0));
+        assert_eq!(child.y_pos_child_key, BigInt::from(y_pos));
+        let child_master_key =
+            master_key.get_child(vec![child.x_pos_child_key, child.y_pos_child_key]);
+        assert_eq!(child.public, child_master_key.public.q);
+    }
+
+    let unknown =
+        test_utils::post_as(&client, customer_id, "/ecdsa/unknown/child".to_string(), &()).await;
+    assert_eq!(unknown.status(), Status::NotFound);
+}
+
+#[rocket::async_test]
+async fn only_allocated_child_keys_sign_when_enforced() {
+    let customer_id = "customer";
+    let config = EngineConfig {
+        enforce_allocated_child_keys: true,
+        ..EngineConfig::default()
+    };
+    let client = test_utils::client_with_config(Arc::new(InMemoryStore::default()), config).await;
+    let (id, master_key) = test_utils::keygen(&client, customer_id).await;
+    let message = BigInt::from(1234);
+
+    // nothing is allocated yet
+    let mut request = test_utils::sign_first(
+        &client,
+        customer_id,
+        &id,
+        &master_key,
+        BigInt::from(0),
+        BigInt::from(0),
+        &message,
+    )
+    .await;
+    let unallocated = test_utils::post_as(
+        &client,
+        customer_id,
+        format!("/ecdsa/sign/{}/second", id),
+        &request,
+    )
+    .await;
+    assert_eq!(unallocated.status(), Status::Forbidden);
+
+    let child: ChildKey =
+        test_utils::post(&client, customer_id, format!("/ecdsa/{}/child", id), &()).await;
+    for (x_pos, y_pos) in [(0, -1), (0, 1), (1, 0)] {
+        request.x_pos_child_key = BigInt::from(x_pos);
+        request.y_pos_child_key = BigInt::from(y_pos);
+        let response = test_utils::post_as(
+            &client,
+            customer_id,
+            format!("/ecdsa/sign/{}/second", id),
+            &request,
+        )
+        .await;
+        assert_eq!(response.status(), Status::Forbidden, "[{}, {}]", x_pos, y_pos);
+    }
+
+    // the rejected requests left the session open for the allocated child key
+    request.x_pos_child_key = child.x_pos_child_key;
+    request.y_pos_child_key = child.y_pos_child_key;
+    let _: party_one::SignatureRecid = test_utils::post(
+        &client,
+        customer_id,
+        format!("/ecdsa/sign/{}/second", id),
+        &request,
+    )
+    .await;
+}
+
+#[rocket::async_test]
+async fn public_keys_match_party_two() {
+    let customer_id = "customer";
+    let (_, client, id, master_key) = test_utils::keygen_in_memory(customer_id).await;
+
+    let public: MasterPublicKey =
+        test_utils::get(&client, customer_id, format!("/ecdsa/{}/public", id)).await;
+    assert_eq!(public.public, master_key.public.q);
+    assert_eq!(public.public_key, compressed_public_key(&master_key.public.q));
+    assert_eq!(public.chain_code, master_key.chain_code);
+    assert!(public.child.is_none());
+
+    let public: MasterPublicKey =
+        test_utils::get(&client, customer_id, format!("/ecdsa/{}/public/0/7", id)).await;
+    let child = public.child.expect("the child key of the path");
+    let child_master_key = master_key.get_child(vec![BigInt::from(0), BigInt::from(7)]);
+    assert_eq!(child.public, child_master_key.public.q);
+    assert_eq!(child.public_key, compressed_public_key(&child_master_key.public.q));
+
+    let other_customer =
+        test_utils::get_as(&client, "other", format!("/ecdsa/{}/public", id)).await;
+    assert_eq!(other_customer.status(), Status::NotFound);
+}
+
+#[rocket::async_test]
+async fn seeded_ids_are_reproducible() {
+    let mut runs = Vec::new();
+    for _ in 0..2 {
+        let client = test_utils::client_with_ids(
+            Arc::new(InMemoryStore::default()),
+            Arc::new(SeededIds::new(7)),
+        )
+        .await;
+        let (first, _) = test_utils::keygen(&client, "customer").await;
+        let (second, _) = test_utils::keygen(&client, "customer").await;
+        assert_ne!(first, second);
+        runs.push((first, second));
+    }
+    assert_eq!(runs[0], runs[1]);
+}
@@ -0,0 +1,151 @@
+use gotham_engine::policy::{
+    CustomerPolicy, HourWindow, Policy, PolicyEngine, PolicySet, VelocityLimit,
+};
+use gotham_engine::types::{TxAuthorizationRequest, TxDecision, TxMetadata};
+
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use two_party_ecdsa::BigInt;
+
+/// Evaluates the tx and reserves it if granted, as sign does
+fn evaluate(
+    engine: &PolicyEngine,
+    metadata: Option<&TxMetadata>,
+    now: DateTime<Utc>,
+) -> TxDecision {
+    let message = BigInt::from(1);
+    let request = TxAuthorizationRequest {
+        customer_id: "customer",
+        key_id: "key",
+        message: &message,
+        child_path: &[],
+        metadata,
+    };
+    engine.reserve(&request, now).unwrap()
+}
+
+fn engine(policy: Policy) -> PolicyEngine {
+    PolicyEngine::new(PolicySet {
+        default: policy,
+        customers: vec![],
+    })
+    .unwrap()
+}
+
+fn tx(amount: u64, destination: &str) -> TxMetadata {
+    TxMetadata {
+        amount: Some(amount),
+        destination: Some(destination.to_string()),
+    }
+}
+
+#[test]
+fn daily_limit() {
+    let engine = engine(Policy {
+        daily_limit: Some(100),
+        ..Default::default()
+    });
+    let now = Utc.with_ymd_and_hms(2023, 6, 1, 12, 0, 0).unwrap();
+
+    assert_eq!(evaluate(&engine, Some(&tx(60, "a")), now), TxDecision::Granted);
+    assert!(matches!(
+        evaluate(&engine, Some(&tx(60, "a")), now),
+        TxDecision::Denied(_)
+    ));
+    assert!(matches!(evaluate(&engine, None, now), TxDecision::Denied(_)));
+    assert_eq!(
+        evaluate(&engine, Some(&tx(60, "a")), now + Duration::days(1)),
+        TxDecision::Granted
+    );
+}
+
+#[test]
+fn destinations_hours_and_velocity() {
+    let engine = engine(Policy {
+        allowed_destinations: Some(vec!["a".to_string()]),
+        allowed_hours: Some(HourWindow { start: 22, end: 6 }),
+        velocity: Some(VelocityLimit {
+            max_txs: 1,
+            window_secs: 60,
+        }),
+        ..Default::default()
+    });
+    let night = Utc.with_ymd_and_hms(2023, 6, 1, 23, 0, 0).unwrap();
+    let noon = Utc.with_ymd_and_hms(2023, 6, 1, 12, 0, 0).unwrap();
+
+    assert!(matches!(
+        evaluate(&engine, Some(&tx(1, "a")), noon),
+        TxDecision::Denied(_)
+    ));
+    assert!(matches!(
+        evaluate(&engine, Some(&tx(1, "b")), night),
+        TxDecision::Denied(_)
+    ));
+    assert_eq!(evaluate(&engine, Some(&tx(1, "a")), night), TxDecision::Granted);
+    assert!(matches!(
+        evaluate(&engine, Some(&tx(1, "a")), night),
+        TxDecision::Denied(_)
+    ));
+    assert_eq!(
+        evaluate(&engine, Some(&tx(1, "a")), night + Duration::seconds(61)),
+        TxDecision::Granted
+    );
+}
+
+#[test]
+fn released_txs_do_not_count() {
+    let engine = engine(Policy {
+        daily_limit: Some(100),
+        velocity: Some(VelocityLimit {
+            max_txs: 1,
+            window_secs: 60,
+        }),
+        ..Default::default()
+    });
+    let now = Utc.with_ymd_and_hms(2023, 6, 1, 12, 0, 0).unwrap();
+    let message = BigInt::from(1);
+    let metadata = tx(60, "a");
+    let request = TxAuthorizationRequest {
+        customer_id: "customer",
+        key_id: "key",
+        message: &message,
+        child_path: &[],
+        metadata: Some(&metadata),
+    };
+
+    // evaluating alone does not count
+    assert_eq!(engine.evaluate(&request, now).unwrap(), TxDecision::Granted);
+    assert_eq!(engine.reserve(&request, now).unwrap(), TxDecision::Granted);
+    assert!(matches!(
+        engine.reserve(&request, now).unwrap(),
+        TxDecision::Denied(_)
+    ));
+
+    // granted but never signed, e.g. sign failed afterwards
+    engine.release(&request).unwrap();
+    assert_eq!(engine.reserve(&request, now).unwrap(), TxDecision::Granted);
+}
+
+#[test]
+fn invalid_hour_windows_are_rejected() {
+    for (start, end) in [(24, 6), (22, 24), (8, 8)] {
+        let policies = PolicySet {
+            default: Policy::default(),
+            customers: vec![CustomerPolicy {
+                customer_id: "customer".to_string(),
+                policy: Policy {
+                    allowed_hours: Some(HourWindow { start, end }),
+                    ..Default::default()
+                },
+            }],
+        };
+        assert!(PolicyEngine::new(policies).is_err());
+    }
+    assert!(PolicyEngine::new(PolicySet {
+        default: Policy {
+            allowed_hours: Some(HourWindow { start: 22, end: 6 }),
+            ..Default::default()
+        },
+        customers: vec![],
+    })
+    .is_ok());
+}
@@ -0,0 +1,59 @@
+//! Runs against a local redis, e.g. `docker run -p 6379:6379 redis:7` and
+//! `REDIS_URL=redis://127.0.0.1:6379 cargo test --test redis_store -- --ignored`
+use gotham_engine::redis_store::RedisStore;
+use gotham_engine::traits::Db;
+use gotham_engine::types::{Alpha, DbIndex, EcdsaStruct};
+use two_party_ecdsa::BigInt;
+
+fn store() -> RedisStore {
+    let url = std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string());
+    RedisStore::new(&url).unwrap()
+}
+
+#[tokio::test]
+#[ignore]
+async fn customers_sharing_a_prefix_are_apart() {
+    let store = store();
+    let run = uuid::Uuid::new_v4().to_string();
+    // `{customer}_{id}` would be `{run}a_b_{run}` for both keys
+    let a = DbIndex {
+        customerId: format!("{}a", run),
+        id: format!("b_{}", run),
+    };
+    let a_b = DbIndex {
+        customerId: format!("{}a_b", run),
+        id: run.clone(),
+    };
+
+    let alpha = Alpha {
+        value: BigInt::from(42),
+    };
+    store.insert(&a_b, &EcdsaStruct::Alpha, &alpha).await.unwrap();
+    assert!(store.get(&a, &EcdsaStruct::Alpha).await.unwrap().is_none());
+
+    // the key of `a`'s share is not the active shares set of `a_b`
+    store
+        .insert(&a_b, &EcdsaStruct::Party1MasterKey, &alpha)
+        .await
+        .unwrap();
+    let a_share = DbIndex {
+        customerId: format!("{}a", run),
+        id: "b".to_string(),
+    };
+    assert!(!store.has_active_share(&a_share.customerId).await.unwrap());
+    store
+        .insert(&a_share, &EcdsaStruct::Party1MasterKey, &alpha)
+        .await
+        .unwrap();
+    assert!(store.has_active_share(&a_share.customerId).await.unwrap());
+
+    store.delete(&a_share, &EcdsaStruct::Party1MasterKey).await.unwrap();
+    assert!(store.has_active_share(&a_b.customerId).await.unwrap());
+    assert!(!store.has_active_share(&a_share.customerId).await.unwrap());
+    for (key, table) in [
+        (&a_b, EcdsaStruct::Alpha),
+        (&a_b, EcdsaStruct::Party1MasterKey),
+    ] {
+        store.delete(key, &table).await.unwrap();
+    }
+}
@@ -0,0 +1,34 @@
+//! `cargo test --features rocksdb,test-utils`
+#![cfg(all(feature = "rocksdb", feature = "test-utils"))]
+
+use gotham_engine::rocksdb_store::RocksDbStore;
+use gotham_engine::test_utils;
+use gotham_engine::traits::Db;
+use gotham_engine::types::{DbIndex, EcdsaStruct};
+use two_party_ecdsa::party_one::HDPos;
+
+use std::sync::Arc;
+
+#[rocket::async_test]
+async fn keygen_and_sign_on_rocksdb() {
+    let dir = tempfile::tempdir().unwrap();
+    test_utils::certify(Arc::new(RocksDbStore::open(dir.path(), "test").unwrap())).await;
+}
+
+#[rocket::async_test]
+async fn customers_sharing_a_prefix_are_apart() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = RocksDbStore::open(dir.path(), "test").unwrap();
+    let key = DbIndex {
+        customerId: "abc_def".to_string(),
+        id: "key_1".to_string(),
+    };
+    store
+        .insert(&key, &EcdsaStruct::Party1MasterKey, &HDPos { pos: 0 })
+        .await
+        .unwrap();
+
+    assert!(store.has_active_share("abc_def").await.unwrap());
+    assert!(!store.has_active_share("abc").await.unwrap());
+    assert_eq!(store.keys(&EcdsaStruct::Party1MasterKey).await.unwrap(), vec![key]);
+}